@@ -0,0 +1,71 @@
+//! Measures `get` and `contains_key` throughput, to compare index hashers.
+//!
+//! Run with `cargo bench --bench get` for SipHash and with
+//! `cargo bench --bench get --features fast-hash` for `FxHasher`. `contains_key` only
+//! touches the index, so it shows the cost of hashing keys most clearly. The store is
+//! kept small enough for the index to stay in cache, or cache misses would dominate.
+
+use std::time::{Duration, Instant};
+
+use kvs::{KvStore, Result};
+use tempfile::TempDir;
+
+const KEYS: usize = 10_000;
+const RUNS: usize = 20;
+
+fn main() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("user:{i:08}")).collect();
+    for key in &keys {
+        store.set(key.clone(), "value".to_owned())?;
+    }
+
+    // look keys up in a scattered order, the same for every run
+    let order: Vec<&String> = (0..KEYS).map(|i| &keys[i * 7_919 % KEYS]).collect();
+
+    let contains = best_of(|| {
+        for key in &order {
+            assert!(store.contains_key(key));
+        }
+        Ok(())
+    })?;
+
+    let get = best_of(|| {
+        for key in &order {
+            store.get(key.as_str())?;
+        }
+        Ok(())
+    })?;
+
+    let hasher = if cfg!(feature = "fast-hash") {
+        "FxHasher"
+    } else {
+        "SipHash"
+    };
+    println!(
+        "{hasher}: contains_key {:.1} ns/op, get {:.1} ns/op",
+        per_op(contains),
+        per_op(get)
+    );
+
+    Ok(())
+}
+
+/// Runs `f` a few times and returns the shortest run.
+fn best_of(mut f: impl FnMut() -> Result<()>) -> Result<Duration> {
+    let mut best = Duration::MAX;
+
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        f()?;
+        best = best.min(start.elapsed());
+    }
+
+    Ok(best)
+}
+
+fn per_op(elapsed: Duration) -> f64 {
+    elapsed.as_nanos() as f64 / KEYS as f64
+}
@@ -0,0 +1,66 @@
+//! Compares reading a store through segment readers with 8 KiB and 1 MiB read buffers.
+//!
+//! Run with `cargo bench --bench read_buffer`. Reports the best of a few runs of
+//! reopening the store, which replays every segment, of exporting every pair in key
+//! order, and of copying the live records with `compact_into`.
+
+use std::time::{Duration, Instant};
+
+use kvs::{KvStore, KvStoreOptions, Result};
+use tempfile::TempDir;
+
+const KEYS: usize = 50_000;
+const VALUE_LEN: usize = 100;
+const RUNS: usize = 5;
+
+fn main() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..KEYS {
+        store.set(format!("key{i:08}"), "v".repeat(VALUE_LEN))?;
+    }
+    drop(store);
+
+    for buffer in [8 * 1024, 1024 * 1024] {
+        let options = KvStoreOptions::new().read_buffer_size(buffer);
+
+        let open = best_of(|| {
+            KvStore::open_with_options(temp_dir.path(), options.clone())?;
+            Ok(())
+        })?;
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        let export = best_of(|| {
+            for key in store.index_snapshot().into_keys() {
+                store.get(&key)?;
+            }
+            Ok(())
+        })?;
+
+        let compact = best_of(|| {
+            let target = TempDir::new()?;
+            store.compact_into(target.path())
+        })?;
+
+        println!(
+            "{:>5} KiB buffer: open {open:?}, export {export:?}, compact_into {compact:?}",
+            buffer / 1024
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs `f` a few times and returns the shortest run.
+fn best_of(mut f: impl FnMut() -> Result<()>) -> Result<Duration> {
+    let mut best = Duration::MAX;
+
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        f()?;
+        best = best.min(start.elapsed());
+    }
+
+    Ok(best)
+}
@@ -0,0 +1,46 @@
+/// A batch of writes applied to a store at once, see `KvStore::write_batch`.
+///
+/// Writes are staged in memory until the batch is written, so reads of the store in
+/// the meantime see its state from before the batch.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+/// A write staged in a `WriteBatch`.
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Stages setting the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    /// Stages removing a given key.
+    ///
+    /// Removing a key that does not exist when the batch is written is a no-op.
+    pub fn remove(&mut self, key: String) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    /// Returns the number of staged writes.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if no writes are staged.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
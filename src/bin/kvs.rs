@@ -1,7 +1,11 @@
 use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::{env, process::exit};
 
-use kvs::{KvStore, KvsError, Result};
+use kvs::{KvStore, KvsError, OpResult, Result, WriteBatch};
 
 #[derive(Parser)]
 #[command(name = "kvs")]
@@ -11,6 +15,14 @@ use kvs::{KvStore, KvsError, Result};
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "The store directory, the current directory by default"
+    )]
+    path: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -39,6 +51,146 @@ enum Commands {
         #[arg(value_name = "KEY", required = true, help = "A string key")]
         key: String,
     },
+
+    #[command(about = "Compact the store and report what was reclaimed")]
+    Compact,
+
+    #[command(about = "Apply the set, rm and get operations of a file, one per line")]
+    Batch {
+        #[arg(value_name = "FILE", required = true, help = "A file of operations")]
+        file: PathBuf,
+    },
+
+    #[command(about = "Write the live pairs of the store to a file, one JSON object per line")]
+    Export {
+        #[arg(value_name = "FILE", required = true, help = "The file to write")]
+        file: PathBuf,
+    },
+
+    #[command(about = "Set the pairs of a file written by export")]
+    Import {
+        #[arg(
+            value_name = "FILE",
+            required = true,
+            help = "A file written by export"
+        )]
+        file: PathBuf,
+    },
+}
+
+/// A live pair, as written by `kvs export` and read by `kvs import`
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// Number of operations of each type applied by `kvs batch`
+#[derive(Debug, Default)]
+struct BatchCounts {
+    sets: u64,
+    removes: u64,
+    gets: u64,
+}
+
+/// Applies the operations in `file` to the store, printing the result of each `get`
+///
+/// The operations run as one `Pipeline`, so each `get` sees the writes before it and all
+/// writes are written with a single flush. Nothing is written if any line is invalid.
+/// Removing a missing key is a no-op and is not counted.
+fn run_batch(store: &mut KvStore, file: &Path) -> Result<BatchCounts> {
+    let mut counts = BatchCounts::default();
+    let mut pipeline = store.pipeline();
+
+    for (n, line) in BufReader::new(File::open(file)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim_start().trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            continue;
+        }
+
+        let (op, rest) = line.split_once(' ').unwrap_or((line, ""));
+        // keys cannot hold spaces, so trailing ones are not part of the key
+        let key = rest.trim_end();
+
+        pipeline = match (op, rest.split_once(' ')) {
+            ("set", Some((key, value))) if !key.is_empty() => {
+                pipeline.set(key.to_owned(), value.to_owned())
+            }
+            ("rm", _) if is_key(key) => pipeline.remove(key.to_owned()),
+            ("get", _) if is_key(key) => pipeline.get(key.to_owned()),
+
+            _ => {
+                let msg = format!("line {}: invalid operation: {line}", n + 1);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+            }
+        };
+    }
+
+    for result in pipeline.execute()? {
+        match result {
+            OpResult::Set => counts.sets += 1,
+            OpResult::Remove(removed) => counts.removes += u64::from(removed),
+            OpResult::Get(value) => {
+                match value {
+                    None => println!("Key not found"),
+                    Some(value) => println!("{value}"),
+                }
+                counts.gets += 1;
+            }
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Returns whether `key` can be a key of a batch operation
+fn is_key(key: &str) -> bool {
+    !key.is_empty() && !key.contains(' ')
+}
+
+/// Writes the live pairs of the store to `file` in key order and returns how many there were
+fn run_export(store: &mut KvStore, file: &Path) -> Result<u64> {
+    let mut out = BufWriter::new(File::create(file)?);
+    let mut count = 0;
+
+    for key in store.index_snapshot().into_keys() {
+        if let Some(value) = store.get(&key)? {
+            serde_json::to_writer(&mut out, &Pair { key, value })?;
+            out.write_all(b"\n")?;
+            count += 1;
+        }
+    }
+
+    out.flush()?;
+    out.get_ref().sync_all()?;
+
+    Ok(count)
+}
+
+/// Sets the pairs in `file` as a single `WriteBatch` and returns how many there were
+///
+/// Nothing is written if any line fails to parse.
+fn run_import(store: &mut KvStore, file: &Path) -> Result<u64> {
+    let mut batch = WriteBatch::new();
+
+    for (n, line) in BufReader::new(File::open(file)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let pair: Pair = serde_json::from_str(&line).map_err(|err| {
+            let msg = format!("line {}: invalid pair: {err}", n + 1);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+        batch.set(pair.key, pair.value);
+    }
+
+    let count = batch.len() as u64;
+    store.write_batch(batch)?;
+
+    Ok(count)
 }
 
 fn main() -> Result<()> {
@@ -46,7 +198,19 @@ fn main() -> Result<()> {
 
     let mut exit_code = 0;
 
-    let mut store = KvStore::open(env::current_dir()?)?;
+    let path = match args.path {
+        Some(path) => path,
+        None => env::current_dir()?,
+    };
+    let mut store = KvStore::open(path)?;
+
+    let writes = matches!(
+        args.command,
+        Commands::Set { .. }
+            | Commands::Remove { .. }
+            | Commands::Batch { .. }
+            | Commands::Import { .. }
+    );
 
     match args.command {
         Commands::Get { key } => match store.get(key) {
@@ -64,16 +228,15 @@ fn main() -> Result<()> {
             }
         },
 
-        Commands::Set { key, value } => match store.set(key, value) {
-            Err(err) => {
+        Commands::Set { key, value } => {
+            if let Err(err) = store.set(key, value) {
                 exit_code = -1;
                 println!("unhandled err: {:?}", err);
             }
-            _ => {}
-        },
+        }
 
         Commands::Remove { key } => match store.remove(key) {
-            Err(KvsError::KeyNotFound) => {
+            Err(KvsError::KeyNotFound(_)) => {
                 exit_code = -1;
                 println!("Key not found");
             }
@@ -85,7 +248,64 @@ fn main() -> Result<()> {
 
             _ => {}
         },
+
+        Commands::Compact => match store.compact() {
+            Ok(result) => {
+                println!(
+                    "reclaimed {} bytes ({} -> {}), {} records rewritten",
+                    result.reclaimed,
+                    result.bytes_before,
+                    result.bytes_after,
+                    result.records_rewritten
+                );
+            }
+
+            Err(err) => {
+                exit_code = -1;
+                println!("unhandled err: {:?}", err);
+            }
+        },
+
+        Commands::Batch { file } => match run_batch(&mut store, &file) {
+            Ok(counts) => {
+                println!(
+                    "{} sets, {} removes, {} gets",
+                    counts.sets, counts.removes, counts.gets
+                );
+            }
+
+            Err(err) => {
+                exit_code = -1;
+                println!("unhandled err: {:?}", err);
+            }
+        },
+
+        Commands::Export { file } => match run_export(&mut store, &file) {
+            Ok(count) => println!("{count} pairs exported"),
+
+            Err(err) => {
+                exit_code = -1;
+                println!("unhandled err: {:?}", err);
+            }
+        },
+
+        Commands::Import { file } => match run_import(&mut store, &file) {
+            Ok(count) => println!("{count} pairs imported"),
+
+            Err(err) => {
+                exit_code = -1;
+                println!("unhandled err: {:?}", err);
+            }
+        },
     };
 
+    // `exit` skips destructors, so make the writes durable first
+    if writes {
+        if let Err(err) = store.flush() {
+            exit_code = -1;
+            println!("unhandled err: {:?}", err);
+        }
+    }
+
     exit(exit_code)
 }
@@ -0,0 +1,58 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{KvsError, Result};
+
+/// Encrypts values before they are written to the log and decrypts them when read.
+///
+/// Implementations should use an authenticated cipher with a fresh nonce per value, e.g.
+/// AES-GCM, so that tampered values fail to decrypt instead of decrypting to garbage.
+/// Keys are stored in plaintext, as they are needed to rebuild the index on open.
+pub trait ValueCipher: Send + Sync {
+    /// Encrypts a value.
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>;
+
+    /// Decrypts a value, returning `None` if it fails to authenticate.
+    fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// A shared `ValueCipher`, so options stay cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Cipher(pub(crate) Arc<dyn ValueCipher>);
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ValueCipher")
+    }
+}
+
+impl Cipher {
+    /// Encrypts a value into the hex string stored in the log.
+    pub(crate) fn seal(&self, value: &str) -> String {
+        self.0
+            .encrypt(value.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Decrypts a hex string from the log.
+    pub(crate) fn open(&self, sealed: &str) -> Result<String> {
+        let bytes = sealed.as_bytes();
+        if !bytes.len().is_multiple_of(2) {
+            return Err(KvsError::Decryption);
+        }
+
+        let ciphertext = bytes
+            .chunks(2)
+            .map(|pair| {
+                let hex = std::str::from_utf8(pair).ok()?;
+                u8::from_str_radix(hex, 16).ok()
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(KvsError::Decryption)?;
+
+        let plaintext = self.0.decrypt(&ciphertext).ok_or(KvsError::Decryption)?;
+        String::from_utf8(plaintext).map_err(|_| KvsError::Decryption)
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Syncs the writes to the active segment on a background thread, at most once per interval.
+///
+/// Writers flush their commands to the operating system and note them with `written`;
+/// durable writers then `wait` until the thread synced everything written so far. The
+/// thread sleeps for the interval, syncs the active segment if anything was written since
+/// the last sync and wakes the waiters. Dropping the committer wakes the thread early to
+/// sync what is left and waits for it to exit.
+pub(crate) struct Committer {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // signalled on every sync and on shutdown
+    cond: Condvar,
+}
+
+struct State {
+    // a handle to the active segment, shared so it can be synced without holding the lock
+    file: Arc<File>,
+    // writes are numbered, these are the last one noted and the last one synced
+    written: u64,
+    synced: u64,
+    // a failed sync fails every later wait, as the written data may be lost
+    error: Option<(io::ErrorKind, String)>,
+    shutdown: bool,
+}
+
+impl Committer {
+    /// Starts syncing writes to `file`, the active segment, every `interval`.
+    pub(crate) fn start(file: File, interval: Duration) -> Committer {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                file: Arc::new(file),
+                written: 0,
+                synced: 0,
+                error: None,
+                shutdown: false,
+            }),
+            cond: Condvar::new(),
+        });
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || shared.run(interval))
+        };
+
+        Committer {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Notes a write flushed to the active segment, to be synced with the next commit.
+    pub(crate) fn written(&self) {
+        self.shared.lock().written += 1;
+    }
+
+    /// Waits until every write noted so far is synced.
+    pub(crate) fn wait(&self) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        let target = state.written;
+
+        while state.synced < target {
+            if let Some((kind, message)) = &state.error {
+                return Err(io::Error::new(*kind, message.clone()));
+            }
+            state = self
+                .shared
+                .cond
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+
+        Ok(())
+    }
+
+    /// Continues with `file` as the active segment, syncing the previous one first.
+    pub(crate) fn switch(&self, file: File) -> io::Result<()> {
+        let mut state = self.shared.lock();
+
+        if state.synced < state.written {
+            state.file.sync_data()?;
+            state.synced = state.written;
+            self.shared.cond.notify_all();
+        }
+        state.file = Arc::new(file);
+
+        Ok(())
+    }
+}
+
+impl Drop for Committer {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.cond.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Runs the commit loop until shutdown.
+    fn run(&self, interval: Duration) {
+        let mut state = self.lock();
+
+        loop {
+            let deadline = Instant::now() + interval;
+            while !state.shutdown {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self
+                    .cond
+                    .wait_timeout(state, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+
+            if state.synced < state.written {
+                let (file, target) = (Arc::clone(&state.file), state.written);
+
+                // writers keep going while the segment syncs
+                drop(state);
+                let res = file.sync_data();
+                state = self.lock();
+
+                match res {
+                    Ok(()) => state.synced = state.synced.max(target),
+                    Err(err) => state.error = Some((err.kind(), err.to_string())),
+                }
+                self.cond.notify_all();
+            }
+
+            if state.shutdown {
+                return;
+            }
+        }
+    }
+}
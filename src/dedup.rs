@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use crate::kv::CommandPosition;
+
+/// Tracks the content-addressed values (blobs) of a store and the keys referencing them.
+///
+/// A blob is kept alive as long as at least one key references it; once its last
+/// reference is dropped its bytes become garbage for compaction to reclaim.
+#[derive(Default)]
+pub(crate) struct Blobs {
+    slots: HashMap<u64, BlobSlot>,
+    refs: HashMap<String, u64>,
+    // reference counts of blobs not known yet, as references may be replayed before
+    // their blob once `KvStore::compact_segment` moved it behind them
+    pending: HashMap<u64, u64>,
+}
+
+/// Position and reference count of a blob.
+pub(crate) struct BlobSlot {
+    pub(crate) position: CommandPosition,
+    pub(crate) refs: u64,
+}
+
+impl Blobs {
+    /// Returns the position of the blob with the given hash.
+    pub(crate) fn position(&self, hash: u64) -> Option<CommandPosition> {
+        self.slots.get(&hash).map(|slot| slot.position)
+    }
+
+    /// Returns the hash of the blob referenced by `key`.
+    pub(crate) fn hash_of(&self, key: &str) -> Option<u64> {
+        self.refs.get(key).copied()
+    }
+
+    /// Records a blob written at `position`, keeping the references of a previous copy.
+    ///
+    /// Returns the position of the replaced copy.
+    pub(crate) fn insert(
+        &mut self,
+        hash: u64,
+        position: CommandPosition,
+    ) -> Option<CommandPosition> {
+        let pending = &mut self.pending;
+        let slot = self.slots.entry(hash).or_insert_with(|| BlobSlot {
+            position,
+            refs: pending.remove(&hash).unwrap_or(0),
+        });
+
+        let old = std::mem::replace(&mut slot.position, position);
+        Some(old).filter(|&old| old != position)
+    }
+
+    /// Makes `key` reference the blob with the given hash.
+    ///
+    /// Returns the position of a blob that lost its last reference, if any.
+    pub(crate) fn assign(&mut self, key: String, hash: u64) -> Option<CommandPosition> {
+        match self.slots.get_mut(&hash) {
+            Some(slot) => slot.refs += 1,
+            None => *self.pending.entry(hash).or_insert(0) += 1,
+        }
+
+        self.refs
+            .insert(key, hash)
+            .and_then(|old| self.release(old))
+    }
+
+    /// Drops the blob reference of `key`, if any.
+    ///
+    /// Returns the position of a blob that lost its last reference, if any.
+    pub(crate) fn unassign(&mut self, key: &str) -> Option<CommandPosition> {
+        self.refs.remove(key).and_then(|hash| self.release(hash))
+    }
+
+    /// Iterates over the positions of all blobs.
+    pub(crate) fn positions(&self) -> impl Iterator<Item = CommandPosition> + '_ {
+        self.slots.values().map(|slot| slot.position)
+    }
+
+    /// Iterates over all blobs.
+    pub(crate) fn slots_mut(&mut self) -> impl Iterator<Item = (&u64, &mut BlobSlot)> {
+        self.slots.iter_mut()
+    }
+
+    /// Forgets the blobs that are not referenced by any key.
+    ///
+    /// Returns the positions of the forgotten blobs.
+    pub(crate) fn retain_referenced(&mut self) -> Vec<CommandPosition> {
+        let mut garbage = Vec::new();
+
+        self.slots.retain(|_, slot| {
+            if slot.refs == 0 {
+                garbage.push(slot.position);
+            }
+            slot.refs > 0
+        });
+
+        garbage
+    }
+
+    fn release(&mut self, hash: u64) -> Option<CommandPosition> {
+        let slot = match self.slots.get_mut(&hash) {
+            None => {
+                if let Some(refs) = self.pending.get_mut(&hash) {
+                    *refs -= 1;
+                    if *refs == 0 {
+                        self.pending.remove(&hash);
+                    }
+                }
+                return None;
+            }
+            Some(slot) => slot,
+        };
+
+        slot.refs -= 1;
+        if slot.refs > 0 {
+            return None;
+        }
+
+        self.slots.remove(&hash).map(|slot| slot.position)
+    }
+}
+
+/// Hashes a value with 64-bit FNV-1a, which is stable across platforms and releases.
+pub(crate) fn hash_value(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
@@ -1,5 +1,8 @@
+// `failure_derive` expands its impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
-use std::{io, result};
+use std::{io, path::PathBuf, result};
 
 /// KvsError
 #[derive(Debug, Fail)]
@@ -12,9 +15,61 @@ pub enum KvsError {
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
 
-    /// Key not found error.
-    #[fail(display = "key not found")]
-    KeyNotFound,
+    /// Key not found error, with the missing key.
+    #[fail(display = "key not found: {}", _0)]
+    KeyNotFound(String),
+
+    /// Write to a store opened read-only.
+    #[fail(display = "store is read-only")]
+    ReadOnly,
+
+    /// A value failed to decrypt, e.g. because it was tampered with.
+    #[cfg(feature = "encryption")]
+    #[fail(display = "value failed to decrypt")]
+    Decryption,
+
+    /// The store path exists but is not a directory, with the path.
+    #[fail(display = "not a directory: {:?}", _0)]
+    NotADirectory(PathBuf),
+
+    /// Segment not found error.
+    #[fail(display = "segment {} not found", _0)]
+    MissingSegment(u64),
+
+    /// No valid record at a given position, with its segment and offset.
+    #[fail(display = "no valid record in segment {} at offset {}", _0, _1)]
+    Corruption(u64, u64),
+
+    /// A write to the store failed because the disk is full, with the IO error.
+    #[fail(display = "{}", _0)]
+    OutOfSpace(#[cause] io::Error),
+
+    /// A read did not finish within `KvStoreOptions::read_timeout`.
+    #[fail(display = "read timed out")]
+    Timeout,
+
+    /// Invalid namespace error, with the namespace or key and what is wrong with it.
+    #[fail(display = "invalid namespace {}", _0)]
+    InvalidNamespace(String),
+
+    /// Invalid key pattern error, with the pattern and what is wrong with it.
+    #[fail(display = "invalid pattern {}", _0)]
+    InvalidPattern(String),
+}
+
+impl KvsError {
+    /// Turns the error of a failed write into `OutOfSpace` if the disk was full.
+    ///
+    /// `ENOSPC` and its equivalents have their own `io::ErrorKind`, which only counts as
+    /// running out of space on the write path.
+    pub(crate) fn on_write(self) -> KvsError {
+        match self {
+            KvsError::Io(err) if err.kind() == io::ErrorKind::StorageFull => {
+                KvsError::OutOfSpace(err)
+            }
+            err => err,
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -23,6 +78,29 @@ impl From<io::Error> for KvsError {
     }
 }
 
+/// Maps errors to the closest `io::ErrorKind`, for callers that return `io::Result`.
+///
+/// `Io` and `OutOfSpace` errors are passed through as they are.
+impl From<KvsError> for io::Error {
+    fn from(err: KvsError) -> io::Error {
+        let kind = match err {
+            KvsError::Io(err) | KvsError::OutOfSpace(err) => return err,
+            KvsError::Serde(_) | KvsError::Corruption(..) => io::ErrorKind::InvalidData,
+            #[cfg(feature = "encryption")]
+            KvsError::Decryption => io::ErrorKind::InvalidData,
+            KvsError::KeyNotFound(_) | KvsError::MissingSegment(_) => io::ErrorKind::NotFound,
+            KvsError::ReadOnly => io::ErrorKind::PermissionDenied,
+            KvsError::NotADirectory(_) => io::ErrorKind::NotADirectory,
+            KvsError::InvalidPattern(_) | KvsError::InvalidNamespace(_) => {
+                io::ErrorKind::InvalidInput
+            }
+            KvsError::Timeout => io::ErrorKind::TimedOut,
+        };
+
+        io::Error::new(kind, err.compat())
+    }
+}
+
 impl From<serde_json::Error> for KvsError {
     fn from(err: serde_json::Error) -> KvsError {
         KvsError::Serde(err)
@@ -0,0 +1,87 @@
+use std::error::Error;
+use std::{fmt, io};
+
+use crate::KvsError;
+
+/// Write boundaries at which a `FaultInjector` is consulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultPoint {
+    /// In `apply`, after a command was flushed but before the index is updated.
+    AfterWrite,
+
+    /// In `compact`, after live commands were copied but before stale segments are removed.
+    AfterCompactionCopy,
+}
+
+/// Simulates crashes at write boundaries for crash-recovery tests.
+///
+/// A store whose operation failed through an injected fault should be dropped and
+/// reopened, just like a process that crashed at that point. Injectors are `Send`, so
+/// stores using them can still be moved between threads.
+pub trait FaultInjector: Send {
+    /// Called at a write boundary; returning an error aborts the operation there.
+    fn inject(&mut self, point: FaultPoint) -> io::Result<()>;
+
+    /// Called before a command of `len` bytes is written.
+    ///
+    /// Returning `Some(n)` writes only the first `n` bytes of the command and fails the write.
+    fn partial_write(&mut self, _len: usize) -> Option<usize> {
+        None
+    }
+
+    /// Called before a command of `len` bytes is written, after `partial_write`.
+    ///
+    /// Returning `Some(n)` writes only the first `n` bytes of the command and fails the write
+    /// with an IO error. Unlike `partial_write` this is not a crash: the store cuts the
+    /// bytes off again and keeps working.
+    fn write_error(&mut self, _len: usize) -> Option<usize> {
+        None
+    }
+
+    /// Returns the kind of the IO error a write failed by `write_error` fails with, e.g.
+    /// `io::ErrorKind::StorageFull` for a full disk.
+    fn write_error_kind(&self) -> io::ErrorKind {
+        io::ErrorKind::Other
+    }
+
+    /// Called the first time the store needs to know whether renames in its directory
+    /// replace their target, see `KvStore::supports_atomic_rename`.
+    ///
+    /// Returning `Some` answers in place of probing the filesystem.
+    fn atomic_rename(&mut self) -> Option<bool> {
+        None
+    }
+}
+
+/// Error returned for a write that was cut short by a `FaultInjector`.
+pub(crate) fn partial_write_error() -> io::Error {
+    io::Error::other(PartialWrite)
+}
+
+/// Error returned for a write failed by `FaultInjector::write_error`.
+pub(crate) fn write_error(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "injected write error")
+}
+
+/// Returns whether `err` is a write cut short by a `FaultInjector`.
+///
+/// It stands for a crash, so the store leaves the torn command in place, like a crashed
+/// process would.
+pub(crate) fn is_partial_write(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(err) => err.get_ref().is_some_and(|err| err.is::<PartialWrite>()),
+        _ => false,
+    }
+}
+
+/// The cause of a `partial_write_error`.
+#[derive(Debug)]
+struct PartialWrite;
+
+impl fmt::Display for PartialWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("injected partial write")
+    }
+}
+
+impl Error for PartialWrite {}
@@ -0,0 +1,84 @@
+#[cfg(not(feature = "fast-hash"))]
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+#[cfg(feature = "fast-hash")]
+use std::hash::{BuildHasherDefault, Hasher};
+
+use crate::kv::CommandPosition;
+
+/// The in-memory index, from keys to the positions of their commands.
+pub(crate) type Index = HashMap<String, CommandPosition, IndexHasher>;
+
+/// Builds the hashers of the index: SipHash with random keys, which resists keys chosen
+/// to collide, unless the `fast-hash` feature swaps in the much cheaper `FxHasher`.
+#[cfg(not(feature = "fast-hash"))]
+pub(crate) type IndexHasher = RandomState;
+
+/// Builds the hashers of the index with the `fast-hash` feature.
+#[cfg(feature = "fast-hash")]
+pub(crate) type IndexHasher = BuildHasherDefault<FxHasher>;
+
+/// Creates an empty index with room for `capacity` keys.
+pub(crate) fn index_with_capacity(capacity: usize) -> Index {
+    Index::with_capacity_and_hasher(capacity, IndexHasher::default())
+}
+
+/// The multiply-rotate hash of the Rust compiler, fast on short keys but trivial to
+/// flood with colliding keys, so only suited to trusted keys.
+///
+/// Hashes match those of `FxHasher` in the `fxhash` and `rustc-hash` 1.x crates on
+/// 64-bit targets.
+#[cfg(feature = "fast-hash")]
+#[derive(Default, Clone, Copy)]
+pub(crate) struct FxHasher {
+    hash: u64,
+}
+
+#[cfg(feature = "fast-hash")]
+impl FxHasher {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(FxHasher::SEED);
+    }
+}
+
+#[cfg(feature = "fast-hash")]
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.add(u64::from_le_bytes(word.try_into().expect("8 bytes")));
+        }
+
+        // the rest in as few words as possible, without copying it into a buffer
+        let mut rest = words.remainder();
+        if let Some((word, tail)) = rest.split_first_chunk::<4>() {
+            self.add(u64::from(u32::from_le_bytes(*word)));
+            rest = tail;
+        }
+        if let Some((word, tail)) = rest.split_first_chunk::<2>() {
+            self.add(u64::from(u16::from_le_bytes(*word)));
+            rest = tail;
+        }
+        if let Some(&byte) = rest.first() {
+            self.add(u64::from(byte));
+        }
+    }
+
+    fn write_u8(&mut self, byte: u8) {
+        self.add(u64::from(byte));
+    }
+
+    fn write_u64(&mut self, word: u64) {
+        self.add(word);
+    }
+
+    fn write_usize(&mut self, word: usize) {
+        self.add(word as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
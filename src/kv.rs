@@ -1,295 +1,3730 @@
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::{KvsError, Result};
+use crate::batch::BatchOp;
+use crate::commit::Committer;
+use crate::dedup::{self, Blobs};
+#[cfg(feature = "fault-injection")]
+use crate::fault::{self, FaultInjector, FaultPoint};
+use crate::hash::{self, Index};
+use crate::lru::Lru;
+use crate::pattern::Pattern;
+use crate::pipeline::Pipeline;
+use crate::readers::Readers;
+use crate::snapshot::{Pins, Snapshot};
+use crate::stats::Counters;
+use crate::value_reader::ValueReader;
+use crate::{
+    CompactionPolicy, CorruptionPolicy, Durability, KvStoreOptions, KvsError, OpCounters, Result,
+    WriteBatch,
+};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024; // 1 MB
 
+/// Number of segments of a tier merged at once by `CompactionPolicy::SizeTiered`
+const TIER_FANOUT: usize = 4;
+/// Segments smaller than this make up the lowest tier
+const TIER_BASE_BYTES: u64 = 64 * 1024; // 64 kB
+
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
 pub struct KvStore {
     path: PathBuf,
+    options: KvStoreOptions,
 
-    buf: BufWriter<File>,
+    // `None` for read-only stores
+    buf: Option<BufWriter<File>>,
 
     offset: u64,
     segment: u64,
     uncompacted: u64,
+    // bytes of stale commands in each segment, including garbage that `uncompacted`
+    // deliberately forgets after compaction
+    garbage: HashMap<u64, u64>,
+    // how many compactions the commands of each compacted segment went through, see
+    // `KvStoreOptions::cold_after_compactions`
+    generations: HashMap<u64, usize>,
+    compaction_threshold: u64,
+
+    index: Index,
+    readers: Readers,
+    blobs: Blobs,
+    modified: HashMap<String, SystemTime>,
+    // positions of the `Remove` commands of removed keys
+    tombstones: HashMap<String, CommandPosition>,
+    // the version of each key written or removed, see `get_versioned`
+    versions: HashMap<String, u64>,
+    // how far each segment was replayed when loading, see `reopen`
+    replayed: HashMap<u64, u64>,
+    // segments pinned by snapshots, which compaction must not remove yet
+    pins: Arc<Pins>,
+
+    // syncs writes in the background with `commit_interval` set, and whether the write
+    // in progress returns without waiting for it
+    committer: Option<Committer>,
+    relaxed: bool,
+    // commands written since the active segment was last synced, see
+    // `Durability::FsyncEvery`
+    unsynced: usize,
+
+    // whether renaming over a file in the store directory replaces it, probed on first use
+    atomic_rename: OnceLock<bool>,
+
+    // access order of the keys with `max_live_bytes` set, `None` otherwise
+    lru: Option<Lru>,
+
+    // reused for reading values in `with_value`
+    scratch: Vec<u8>,
+
+    counters: Counters,
+
+    #[cfg(feature = "fault-injection")]
+    fault_injector: Option<Box<dyn FaultInjector>>,
 
-    index: HashMap<String, CommandPosition>,
-    readers: HashMap<u64, BufReader<File>>,
+    // removed on drop, after the segment files are closed
+    #[cfg(feature = "tempfile")]
+    temp_dir: Option<tempfile::TempDir>,
 }
 
 impl KvStore {
     /// Creates a `KvStore`.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Creates a `KvStore` with the given options.
+    ///
+    /// A store must always be reopened with the same segment naming options it was created with.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        Ok(KvStore::open_with_report(path, options)?.0)
+    }
+
+    /// Creates a `KvStore` with the given options and reports the state recovered from disk.
+    pub fn open_with_report(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<(KvStore, RecoveryReport)> {
         let path: PathBuf = path.into();
+        check_options(&options)?;
 
         // create directory if required
-        fs::create_dir_all(&path)?;
+        create_store_dir(&path)?;
+
+        let mut loaded = load_store(&path, &options)?;
+
+        // prepare new segment log buffer
+        let last = loaded.segments.last().copied().unwrap_or(0);
+        let (segment, buf) = create_next_segment(&path, &options, last)?;
+        sync_dir_if_durable(&path, &options)?;
 
-        let mut uncompacted = 0;
-        let segments = sorted_segments(&path)?;
-        let mut index = HashMap::new();
-        let mut readers = HashMap::new();
+        // add newest segment to readers
+        loaded
+            .readers
+            .insert(segment, segment_reader(&path, &options, segment)?);
+
+        let committer = match options.commit_interval {
+            None => None,
+            Some(interval) => Some(Committer::start(buf.get_ref().try_clone()?, interval)),
+        };
 
-        for &segment in &segments {
-            uncompacted += load_segment(&path, segment, &mut index, &mut readers)?;
+        let report = loaded.report.clone();
+        let mut store = KvStore::from_loaded(path, options, Some(buf), segment, loaded);
+        store.committer = committer;
+        if store.options.max_live_bytes.is_some() {
+            store.lru = Some(store.lru_by_write_time());
         }
 
-        let segment = segments.last().unwrap_or(&0) + 1;
+        Ok((store, report))
+    }
 
-        // prepare new segment log buffer
-        let buf = new_segment(&path, segment)?;
+    /// Creates a `KvStore` in a new temporary directory, which is removed when the store is dropped.
+    #[cfg(feature = "tempfile")]
+    pub fn open_temp() -> Result<KvStore> {
+        let temp_dir = tempfile::TempDir::new()?;
 
-        // add newest segment to readers
-        readers.insert(segment, segment_reader(&path, segment)?);
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.temp_dir = Some(temp_dir);
 
-        Ok(KvStore {
-            path,
-            buf,
-            offset: 0,
-            uncompacted,
-            segment,
-            index,
-            readers,
-        })
+        Ok(store)
     }
 
-    /// Applies the command to the log and in-memory index.
-    fn apply(&mut self, cmd: Command) -> Result<()> {
-        let res = serde_json::to_vec(&cmd)?;
-        self.buf.write(&res)?;
-        self.buf.flush()?;
+    /// Returns the directory of the store.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether renaming a file over another in the store directory replaces it.
+    ///
+    /// Writable stores probe this the first time it is needed, by renaming a scratch file
+    /// over another and checking the result, and keep the answer. Read-only stores do not
+    /// write to probe it and always return `false`. Compaction writes the compacted segment
+    /// to a scratch file and renames it into place, so a crash never leaves a partial
+    /// segment behind; where renames may not replace their target, e.g. on some network
+    /// filesystems, it writes the compacted segment in place instead.
+    pub fn supports_atomic_rename(&self) -> bool {
+        if self.buf.is_none() {
+            return false;
+        }
+
+        *self
+            .atomic_rename
+            .get_or_init(|| probe_atomic_rename(&self.path))
+    }
 
-        let cmd_length = res.len() as u64;
+    /// Returns whether compaction can rename the compacted segment into place.
+    fn renames_atomically(&mut self) -> bool {
+        #[cfg(feature = "fault-injection")]
+        if self.atomic_rename.get().is_none() {
+            let injected = self
+                .fault_injector
+                .as_mut()
+                .and_then(|injector| injector.atomic_rename());
+            if let Some(atomic) = injected {
+                let _ = self.atomic_rename.set(atomic);
+            }
+        }
 
-        let old = match cmd {
-            Command::Remove { key } => self.index.remove(&key),
+        self.supports_atomic_rename()
+    }
 
-            Command::Set { key, value: _ } => self
-                .index
-                .insert(key, CommandPosition(self.segment, self.offset, cmd_length)),
+    /// Returns the offset the next command is written at in the active segment.
+    ///
+    /// This is the length the store believes the active segment has. Compare it with
+    /// `active_segment_file_len` to check that the log on disk matches. Read-only stores
+    /// do not write and report 0.
+    pub fn active_segment_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the length of the active segment file on disk.
+    ///
+    /// Buffered commands are not flushed first. Writes flush as they go, so this matches
+    /// `active_segment_offset` unless writing the log failed, e.g. a flush that only made
+    /// it to disk in part. Read-only stores report the length of the newest segment.
+    pub fn active_segment_file_len(&mut self) -> Result<u64> {
+        let metadata = match &self.buf {
+            Some(buf) => buf.get_ref().metadata()?,
+            None => fs::metadata(segment_path(&self.path, &self.options, self.segment))?,
         };
 
-        if let Some(position) = old {
-            self.uncompacted += position.2;
+        Ok(metadata.len())
+    }
+
+    /// Asks the filesystem to allocate `bytes` more bytes for the active segment.
+    ///
+    /// This is a layout hint for bursts of writes: blocks allocated up front can be laid
+    /// out contiguously, which keeps later sequential reads of the segment fast. The file
+    /// keeps its length, so the offset and the records of the segment are left as they
+    /// are, and appending simply fills the allocated blocks. The space is released once
+    /// the segment is compacted away. On Linux this preallocates with `fallocate`; on
+    /// other platforms, and filesystems that do not support it, this does nothing.
+    pub fn reserve_segment_space(&mut self, bytes: u64) -> Result<()> {
+        let buf = self.buf.as_ref().ok_or(KvsError::ReadOnly)?;
+
+        Ok(preallocate(buf.get_ref(), self.offset, bytes)?)
+    }
+
+    /// Opens an existing store for reading only.
+    ///
+    /// Any number of read-only stores may be opened next to a single writer. They see the
+    /// state of the store as of opening, or of the last `refresh`. Writes fail with `ReadOnly`.
+    pub fn open_read_only(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path: PathBuf = path.into();
+        check_options(&options)?;
+        if path.exists() && !path.is_dir() {
+            return Err(KvsError::NotADirectory(path));
         }
 
-        self.offset += cmd_length;
+        let loaded = load_store(&path, &options)?;
+        let segment = loaded.segments.last().copied().unwrap_or(0);
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        Ok(KvStore::from_loaded(path, options, None, segment, loaded))
+    }
+
+    /// Reloads a read-only store to pick up changes made by the writer since it was opened.
+    ///
+    /// The whole store is reloaded, as compactions may have replaced any segment. Commands
+    /// the writer is still in the middle of writing are skipped like torn writes and picked
+    /// up by a later refresh. Segments compacted away while reloading cause a retry.
+    /// Writable stores are always up to date, so this is a no-op for them.
+    pub fn refresh(&mut self) -> Result<()> {
+        if self.buf.is_some() {
+            return Ok(());
         }
 
+        let mut attempts = 0;
+        let loaded = loop {
+            match load_store(&self.path, &self.options) {
+                Err(KvsError::Io(err)) if err.kind() == io::ErrorKind::NotFound && attempts < 3 => {
+                    attempts += 1;
+                }
+                res => break res?,
+            }
+        };
+
+        let segment = loaded.segments.last().copied().unwrap_or(0);
+        let counters = mem::take(&mut self.counters);
+        *self = KvStore::from_loaded(
+            mem::take(&mut self.path),
+            self.options.clone(),
+            None,
+            segment,
+            loaded,
+        );
+        self.counters = counters;
+
         Ok(())
     }
 
-    /// Sets the value of a string key to a string.
+    /// Picks up changes made by the writer since a read-only store was opened or reloaded.
     ///
-    /// If the key already exists, the previous value will be overwritten.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.apply(Command::Set { key, value })
+    /// Unlike `refresh`, only what was appended since is replayed: the grown tails of
+    /// known segments and any new segments. If a compaction removed segments, the whole
+    /// store is reloaded after all. Writable stores are always up to date, so this is a
+    /// no-op for them.
+    pub fn reopen(&mut self) -> Result<()> {
+        if self.buf.is_some() {
+            return Ok(());
+        }
+
+        let on_disk = sorted_segments(&self.path, &self.options)?;
+        let known = self.readers.segments();
+        if known
+            .iter()
+            .any(|segment| on_disk.binary_search(segment).is_err())
+        {
+            return self.refresh();
+        }
+
+        let mut loaded = LoadedStore {
+            segments: on_disk,
+            index: mem::take(&mut self.index),
+            readers: mem::replace(&mut self.readers, Readers::new(&self.path, &self.options)),
+            blobs: mem::take(&mut self.blobs),
+            modified: mem::take(&mut self.modified),
+            tombstones: mem::take(&mut self.tombstones),
+            versions: mem::take(&mut self.versions),
+            garbage: mem::take(&mut self.garbage),
+            replayed: mem::take(&mut self.replayed),
+            report: RecoveryReport::default(),
+        };
+
+        let res = self.load_appended(&mut loaded);
+
+        // put the state back even on failure, it is consistent up to the failed segment
+        self.index = loaded.index;
+        self.readers = loaded.readers;
+        self.blobs = loaded.blobs;
+        self.modified = loaded.modified;
+        self.tombstones = loaded.tombstones;
+        self.versions = loaded.versions;
+        self.garbage = loaded.garbage;
+        self.replayed = loaded.replayed;
+        self.segment = loaded.segments.last().copied().unwrap_or(0);
+
+        res
     }
 
-    /// Remove a given key.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if !self.index.contains_key(&key) {
-            return Err(KvsError::KeyNotFound);
+    /// Replays the commands appended to the segments of `loaded` since they were last loaded.
+    fn load_appended(&mut self, loaded: &mut LoadedStore) -> Result<()> {
+        for segment in loaded.segments.clone() {
+            let start = loaded.replayed.get(&segment).copied().unwrap_or(0);
+
+            let len = fs::metadata(segment_path(&self.path, &self.options, segment))?.len();
+            if start > 0 && len <= start {
+                continue;
+            }
+
+            let load = load_segment(&self.path, &self.options, segment, start, loaded)?;
+            self.uncompacted += load.uncompacted;
         }
 
-        self.apply(Command::Remove { key })
+        Ok(())
     }
 
-    /// Gets the string value of a given string key.
+    /// Assembles a store from its loaded state
+    fn from_loaded(
+        path: PathBuf,
+        options: KvStoreOptions,
+        buf: Option<BufWriter<File>>,
+        segment: u64,
+        loaded: LoadedStore,
+    ) -> KvStore {
+        KvStore {
+            path,
+            options,
+            buf,
+            offset: 0,
+            uncompacted: loaded.report.uncompacted_bytes,
+            garbage: loaded.garbage,
+            generations: HashMap::new(),
+            compaction_threshold: COMPACTION_THRESHOLD,
+            segment,
+            index: loaded.index,
+            readers: loaded.readers,
+            blobs: loaded.blobs,
+            modified: loaded.modified,
+            tombstones: loaded.tombstones,
+            versions: loaded.versions,
+            replayed: loaded.replayed,
+            pins: Arc::default(),
+            committer: None,
+            relaxed: false,
+            unsynced: 0,
+            atomic_rename: OnceLock::new(),
+            lru: None,
+            scratch: Vec::new(),
+            counters: Counters::default(),
+            #[cfg(feature = "fault-injection")]
+            fault_injector: None,
+            #[cfg(feature = "tempfile")]
+            temp_dir: None,
+        }
+    }
+
+    /// Estimates the memory used by the in-memory index of a store with `num_keys` keys.
     ///
-    /// Returns `None` if the given key does not exist.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if !self.index.contains_key(&key) {
-            return Ok(None);
+    /// This accounts for the hash table buckets and a copy of each key for the positions,
+    /// the write times and the versions, but not for allocator overhead.
+    pub fn estimated_index_bytes(num_keys: usize, avg_key_len: usize) -> usize {
+        if num_keys == 0 {
+            return 0;
         }
 
-        let position = self.index.get(&key).unwrap();
-        Ok(read_value(&mut self.readers, position.0, position.1)?)
+        // hash tables keep at most 7/8 of their buckets full, plus one control byte each
+        let buckets = (num_keys * 8 / 7).next_power_of_two();
+        let positions = buckets * (mem::size_of::<(String, CommandPosition)>() + 1);
+        let modified = buckets * (mem::size_of::<(String, SystemTime)>() + 1);
+        let versions = buckets * (mem::size_of::<(String, u64)>() + 1);
+
+        positions + modified + versions + 3 * num_keys * avg_key_len
     }
 
-    /// Compacts the storage
-    pub fn compact(&mut self) -> Result<()> {
-        let mut compact_offset = 0;
-        let compact_segment = self.segment + 1;
+    /// Releases memory the in-memory index holds beyond what its keys need.
+    ///
+    /// Hash maps keep their capacity as keys are removed, so after removing many keys a
+    /// long-running process can reclaim the memory with this instead of reopening the
+    /// store. This shrinks the positions, write times, versions and tombstones of keys,
+    /// and the garbage counts of segments; the segments themselves are kept in a tree,
+    /// which frees memory as they go. Safe to call at any time.
+    pub fn shrink_index(&mut self) {
+        self.index.shrink_to_fit();
+        self.modified.shrink_to_fit();
+        self.versions.shrink_to_fit();
+        self.tombstones.shrink_to_fit();
+        self.garbage.shrink_to_fit();
+    }
 
-        let mut compact_buf = new_segment(&self.path, compact_segment)?;
+    /// Sets the fault injector consulted at write boundaries.
+    #[cfg(feature = "fault-injection")]
+    pub fn set_fault_injector(&mut self, injector: impl FaultInjector + 'static) {
+        self.fault_injector = Some(Box::new(injector));
+    }
+
+    /// Fails with the error of the fault injector at `point`, if any.
+    #[cfg(feature = "fault-injection")]
+    fn inject(&mut self, point: FaultPoint) -> Result<()> {
+        match self.fault_injector.as_mut() {
+            Some(injector) => Ok(injector.inject(point)?),
+            None => Ok(()),
+        }
+    }
 
-        for position in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&position.0)
-                .expect("segment reader not found");
+    /// Applies the command to the log and in-memory index.
+    ///
+    /// The index is only updated once the command is written; if writing fails, the log
+    /// is cut back to where it was and the index is left alone.
+    fn apply(&mut self, cmd: Command) -> Result<()> {
+        let position = self.write_command(&cmd)?;
+        self.index_written(cmd, position)
+    }
 
-            reader.seek(SeekFrom::Start(position.1))?;
+    /// Applies a command already written at `position` to the in-memory index.
+    fn index_written(&mut self, cmd: Command, position: CommandPosition) -> Result<()> {
+        self.count_command(&cmd);
 
-            let mut cmd_reader = reader.take(position.2);
+        let key = self.lru.as_ref().and(cmd.key()).map(str::to_owned);
+        self.uncompacted += index_command(
+            &mut self.index,
+            &mut self.blobs,
+            &mut self.modified,
+            &mut self.tombstones,
+            &mut self.versions,
+            &mut self.garbage,
+            cmd,
+            position,
+        );
 
-            io::copy(&mut cmd_reader, &mut compact_buf)?;
+        if let Some(key) = key {
+            self.track_access(&key);
+            self.evict_over_budget()?;
+        }
 
-            *position = CommandPosition(compact_segment, compact_offset, position.2);
-            compact_offset += position.2; // update new offset
+        if self.options.auto_compact && self.should_compact() {
+            self.compact()?;
         }
 
-        compact_buf.flush()?;
+        Ok(())
+    }
 
-        // reset segment
-        self.offset = 0;
-        self.segment += 2; // next after compaction
-        self.uncompacted = 0;
-        self.buf = new_segment(&self.path, self.segment)?;
+    /// Records a key as just written in the access order, or forgets it if it was removed.
+    fn track_access(&mut self, key: &str) {
+        let bytes = self.value_len(key);
 
-        // add newest segment to readers
-        self.readers
-            .insert(self.segment, segment_reader(&self.path, self.segment)?);
+        if let Some(lru) = self.lru.as_mut() {
+            match bytes {
+                Some(bytes) => lru.insert(key, bytes),
+                None => lru.remove(key),
+            }
+        }
+    }
 
-        // remove stale log files.
-        let stale_segments: Vec<_> = self
-            .readers
+    /// Returns the access order of the keys as of opening, oldest written first.
+    fn lru_by_write_time(&self) -> Lru {
+        let mut keys: Vec<(SystemTime, &String)> = self
+            .index
             .keys()
-            .filter(|&&segment| segment < compact_segment)
-            .cloned()
+            .map(|key| (self.modified.get(key).copied().unwrap_or(UNIX_EPOCH), key))
             .collect();
+        keys.sort_unstable();
+
+        let mut lru = Lru::default();
+        for (_, key) in keys {
+            lru.insert(key, self.value_len(key).unwrap_or(0));
+        }
+
+        lru
+    }
+
+    /// Removes the least recently used keys until the live bytes are within `max_live_bytes`.
+    ///
+    /// The most recently used key is never removed, even if it alone is over the budget.
+    /// Keys are only forgotten by the access order once their removal is written, so a
+    /// failed write leaves them to be evicted by a later one.
+    fn evict_over_budget(&mut self) -> Result<()> {
+        let evicted = match (self.lru.as_ref(), self.options.max_live_bytes) {
+            (Some(lru), Some(budget)) => lru.over_budget(budget),
+            _ => return Ok(()),
+        };
+        if evicted.is_empty() {
+            return Ok(());
+        }
+
+        let written = self.write_or_undo(|store| {
+            let mut written = Vec::with_capacity(evicted.len());
+            for key in &evicted {
+                let key = key.clone();
+                let version = store.next_version(&key);
+                let cmd = Command::Remove { key, version };
+                let position = store.append_command(&cmd)?;
+                written.push((cmd, position));
+            }
+
+            store.flush_buf()?;
+            Ok(written)
+        })?;
 
-        for segment in stale_segments {
-            self.readers.remove(&segment);
-            fs::remove_file(segment_path(&self.path, segment))?;
+        for (cmd, position) in written {
+            self.uncompacted += index_command(
+                &mut self.index,
+                &mut self.blobs,
+                &mut self.modified,
+                &mut self.tombstones,
+                &mut self.versions,
+                &mut self.garbage,
+                cmd,
+                position,
+            );
+        }
+        for key in &evicted {
+            self.track_access(key);
         }
 
         Ok(())
     }
-}
 
-/// Constructs a path to a segment file by combining the base path with a segment number
-/// Returns a `PathBuf` representing the full path to the segment file (e.g., "/base/path/123.log")
-fn segment_path(path: &Path, segment: u64) -> PathBuf {
-    path.join(format!("{segment}.log"))
-}
+    /// Counts a written command towards the operation counters.
+    fn count_command(&self, cmd: &Command) {
+        match cmd {
+            Command::Set { .. } | Command::SetRef { .. } => self.counters.set(),
+            Command::Remove { .. } => self.counters.remove(),
+            Command::Blob { .. } => {}
+        }
+    }
 
-/// Creates a new segment file and returns a buffered writer to it
-fn new_segment(path: &Path, segment: u64) -> Result<BufWriter<File>> {
-    Ok(BufWriter::with_capacity(
-        500 * 1024, // 500 kB
-        OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(segment_path(path, segment))?,
-    ))
-}
+    /// Returns the operation counts since the store was opened or `reset_stats` was called.
+    pub fn op_counters(&self) -> OpCounters {
+        self.counters.snapshot()
+    }
 
-/// Reads a value from a specific offset in a segment file
-fn read_value(
-    readers: &mut HashMap<u64, BufReader<File>>,
-    segment: u64,
-    offset: u64,
-) -> Result<Option<String>> {
-    let reader = match readers.get_mut(&segment) {
-        None => return Ok(None),
-        Some(reader) => reader,
-    };
+    /// Sets all operation counts back to zero, e.g. between benchmark runs.
+    pub fn reset_stats(&self) {
+        self.counters.reset();
+    }
 
-    reader.seek(SeekFrom::Start(offset))?;
+    /// Writes the command to the active segment and returns its position.
+    fn write_command(&mut self, cmd: &Command) -> Result<CommandPosition> {
+        let position = self.write_or_undo(|store| {
+            let position = store.append_command(cmd)?;
+            store.flush_buf()?;
+            Ok(position)
+        })?;
 
-    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+        #[cfg(feature = "fault-injection")]
+        self.inject(FaultPoint::AfterWrite)?;
+
+        Ok(position)
+    }
 
-    if let Some(res) = stream.next() {
-        match res? {
-            Command::Set { key: _, value } => return Ok(Some(value)),
-            _ => {}
+    /// Flushes and syncs all writes to disk, regardless of the durability option.
+    ///
+    /// Call this before shutting down to make sure no acknowledged write is lost.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(buf) = self.buf.as_mut() {
+            buf.flush()
+                .and_then(|()| buf.get_ref().sync_all())
+                .map_err(|err| KvsError::from(err).on_write())?;
+            self.unsynced = 0;
         }
+
+        Ok(())
     }
 
-    Ok(None)
-}
+    /// Flushes buffered commands to the active segment, syncing them if durability requires it.
+    fn flush_buf(&mut self) -> Result<()> {
+        let buf = self.buf.as_mut().ok_or(KvsError::ReadOnly)?;
+        buf.flush()?;
 
-// Creates a buffered reader for the segment
-fn segment_reader(path: &Path, segment: u64) -> Result<BufReader<File>> {
-    Ok(BufReader::new(File::open(segment_path(&path, segment))?))
-}
+        match &self.committer {
+            Some(committer) => {
+                committer.written();
+                if !self.relaxed {
+                    committer.wait()?;
+                }
+            }
+            None => match self.options.durability {
+                Durability::Sync => buf.get_ref().sync_data()?,
+                Durability::FsyncEvery(writes) if self.unsynced >= writes => self.sync_active()?,
+                _ => {}
+            },
+        }
 
-/// Loads a segment file into the index map
-fn load_segment(
-    path: &Path,
-    segment: u64,
-    index: &mut HashMap<String, CommandPosition>,
-    readers: &mut HashMap<u64, BufReader<File>>,
-) -> Result<u64> {
-    let reader = segment_reader(path, segment)?;
-    let mut stream = serde_json::Deserializer::from_reader(reader.get_ref()).into_iter::<Command>();
+        Ok(())
+    }
 
-    let mut offset: u64 = 0;
-    let mut uncompacted = 0;
+    /// Syncs the active segment to disk and starts counting unsynced commands anew.
+    fn sync_active(&mut self) -> Result<()> {
+        if let Some(buf) = self.buf.as_mut() {
+            buf.flush()?;
+            buf.get_ref().sync_data()?;
+        }
+        self.unsynced = 0;
 
-    while let Some(cmd) = stream.next() {
-        let current_offset = stream.byte_offset() as u64;
+        Ok(())
+    }
 
-        let old = match cmd? {
-            Command::Remove { key } => index.remove(&key),
+    /// Runs a write to the active segment, undoing whatever it left behind if it fails.
+    ///
+    /// `write` must not touch the index, which is only updated once the write succeeded.
+    fn write_or_undo<T>(&mut self, write: impl FnOnce(&mut KvStore) -> Result<T>) -> Result<T> {
+        let offset = self.offset;
+        let res = write(self);
 
-            Command::Set { key, value: _ } => {
-                let cmd_len = current_offset - offset;
-                index.insert(key, CommandPosition(segment, offset, cmd_len))
+        if res.is_err() {
+            #[cfg(feature = "fault-injection")]
+            if res.as_ref().is_err_and(fault::is_partial_write) {
+                return res;
             }
+
+            self.undo_write(offset)?;
+        }
+
+        res.map_err(KvsError::on_write)
+    }
+
+    /// Cuts the active segment back to `offset`, dropping what a failed write left behind.
+    ///
+    /// Bytes still buffered are discarded and any that reached the file are truncated, so
+    /// the log ends with the last write that succeeded and the next one lands at `offset`.
+    fn undo_write(&mut self, offset: u64) -> Result<()> {
+        self.offset = offset;
+
+        let buf = match self.buf.take() {
+            None => return Ok(()),
+            Some(buf) => buf,
+        };
+
+        let capacity = buf.capacity();
+        let (file, _) = buf.into_parts();
+        let res = file.set_len(offset);
+        self.buf = Some(BufWriter::with_capacity(capacity, file));
+
+        Ok(res?)
+    }
+
+    /// Buffers the command for the active segment and returns its position.
+    fn append_command(&mut self, cmd: &Command) -> Result<CommandPosition> {
+        let res = serde_json::to_vec(cmd)?;
+        let buf = self.buf.as_mut().ok_or(KvsError::ReadOnly)?;
+
+        #[cfg(feature = "fault-injection")]
+        if let Some(len) = self
+            .fault_injector
+            .as_mut()
+            .and_then(|f| f.partial_write(res.len()))
+        {
+            buf.write_all(&res[..len.min(res.len())])?;
+            buf.flush()?;
+            return Err(fault::partial_write_error().into());
+        }
+
+        #[cfg(feature = "fault-injection")]
+        if let Some(len) = self
+            .fault_injector
+            .as_mut()
+            .and_then(|f| f.write_error(res.len()))
+        {
+            let kind = self
+                .fault_injector
+                .as_ref()
+                .map_or(io::ErrorKind::Other, |f| f.write_error_kind());
+            buf.write_all(&res[..len.min(res.len())])?;
+            buf.flush()?;
+            return Err(fault::write_error(kind).into());
+        }
+
+        buf.write_all(&res)?;
+        self.unsynced += 1;
+
+        let position = CommandPosition {
+            segment: self.segment,
+            offset: self.offset,
+            len: res.len() as u64,
         };
+        self.offset += position.len;
+
+        Ok(position)
+    }
 
-        // key either
-        // - already existed, we can reclaim space of the old command
-        // - was removed, space can be reclaimed
-        if let Some(position) = old {
-            uncompacted += position.2;
+    /// Encrypts a value for the log, if the store has a cipher.
+    fn seal_value(&self, value: String) -> String {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.options.cipher {
+            return cipher.seal(&value);
         }
 
-        offset = current_offset;
+        value
+    }
+
+    /// Decrypts a value read from the log, if the store has a cipher.
+    fn open_value<'a>(&self, value: &'a str) -> Result<Cow<'a, str>> {
+        open_value(&self.options, value)
     }
 
-    readers.insert(segment, reader);
+    /// Reads the value of the command at `position`, following blob references.
+    fn read_position(&mut self, position: CommandPosition) -> Result<Option<String>> {
+        let cmd = match self.read_timeout_for(position.segment) {
+            Some(timeout) => serde_json::from_slice(&self.read_record_timed(position, timeout)?)?,
+            None => read_command(&mut self.readers, position.segment, position.offset)?,
+        };
 
-    Ok(uncompacted)
-}
+        match cmd {
+            Command::Set { value, .. } | Command::Blob { value, .. } => {
+                Ok(Some(self.open_value(&value)?.into_owned()))
+            }
 
-/// Returns a sorted list of all segment numbers in the directory
-fn sorted_segments(path: &Path) -> Result<Vec<u64>> {
-    let mut entries: Vec<u64> = fs::read_dir(path)?
-        .flat_map(|f| -> Result<_> { Ok(f?.path()) })
-        .filter(|f| f.is_file() && f.extension() == Some("log".as_ref()))
-        .flat_map(|f| {
-            f.file_name()
-                .and_then(OsStr::to_str)
-                .map(|f| f.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
+            Command::SetRef { hash, .. } => match self.blobs.position(hash) {
+                Some(blob) => self.read_position(blob),
+                None => Ok(None),
+            },
+
+            Command::Remove { .. } => Ok(None),
+        }
+    }
+
+    /// Returns the timeout for reading from `segment`, if reads from it should be timed.
+    fn read_timeout_for(&self, segment: u64) -> Option<Duration> {
+        self.options
+            .read_timeout
+            .filter(|_| self.readers.contains(segment))
+    }
+
+    /// Reads the bytes of the command at `position` on a thread of its own, giving up
+    /// after `timeout`.
+    ///
+    /// File reads cannot be cancelled, so the segment is opened anew for the thread, which
+    /// is left behind if it does not finish in time, still holding its own file handle.
+    fn read_record_timed(&self, position: CommandPosition, timeout: Duration) -> Result<Vec<u8>> {
+        let path = segment_path(&self.path, &self.options, position.segment);
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let read = || -> io::Result<Vec<u8>> {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(position.offset))?;
+
+                let mut bytes = vec![0; position.len as usize];
+                file.read_exact(&mut bytes)?;
+
+                Ok(bytes)
+            };
+
+            // the receiver is gone if the read timed out
+            let _ = tx.send(read());
+        });
+
+        match rx.recv_timeout(timeout) {
+            Ok(res) => Ok(res?),
+            Err(RecvTimeoutError::Timeout) => Err(KvsError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::other("read thread panicked").into())
+            }
+        }
+    }
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten. With
+    /// `commit_interval` set, this returns once the write is synced by the next commit.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let key = self.normalize_owned(key);
+        if self.options.dedup_values {
+            return self.set_dedup(key, value);
+        }
+
+        let modified = now_millis();
+        self.apply(Command::Set {
+            version: self.next_version(&key),
+            key,
+            value: self.seal_value(value),
+            modified,
         })
-        .flatten()
-        .collect();
+    }
 
-    entries.sort();
+    /// Sets the value of a string key like `set` and returns where its record landed.
+    ///
+    /// Returns the `(segment, offset, length)` of the record in the log, as kept in the
+    /// index, for external indexes to read it back with `get_at`. The record is always a
+    /// plain value, even with `dedup_values` enabled, so the position holds the value
+    /// itself. Compaction moves records, so positions go stale once a compaction runs,
+    /// including one triggered by a later write; consumers must handle relocation, e.g.
+    /// by looking up the key again.
+    pub fn set_and_locate(&mut self, key: String, value: String) -> Result<(u64, u64, u64)> {
+        let key = self.normalize_owned(key);
+        let cmd = Command::Set {
+            version: self.next_version(&key),
+            key: key.clone(),
+            value: self.seal_value(value),
+            modified: now_millis(),
+        };
+        let written = self.write_command(&cmd)?;
+        self.index_written(cmd, written)?;
 
-    Ok(entries)
-}
+        // a compaction triggered by this very write moved the record already
+        let position = self.index.get(&key).copied().unwrap_or(written);
 
-/// Represents the commands that can be stored in the log files
-///
-/// Each command is serialized to JSON and written to the log files.
-/// - Set: Stores a key-value pair
-/// - Remove: Removes a key and its associated value
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
-}
+        Ok((position.segment, position.offset, position.len))
+    }
 
-/// Represents the command position in a segment
-///
-/// Format: (segment, offset, length)
-struct CommandPosition(u64, u64, u64);
+    /// Sets the value of a string key like `set`, without waiting for it to be synced.
+    ///
+    /// With `commit_interval` set this returns as soon as the write is handed to the
+    /// operating system, and the write is synced with the next commit. Without it, this is
+    /// the same as `set`.
+    pub fn set_relaxed(&mut self, key: String, value: String) -> Result<()> {
+        self.relaxed = true;
+        let res = self.set(key, value);
+        self.relaxed = false;
+
+        res
+    }
+
+    /// Sets the value of a string key to the UTF-8 text read from `r`.
+    ///
+    /// The value is escaped and streamed into the log as it is read, so it is never held
+    /// in memory as a whole. If reading fails, or the text is not valid UTF-8, nothing is
+    /// stored. With `dedup_values` or encryption enabled the value is read into memory first.
+    pub fn set_from_reader<R: Read>(&mut self, key: String, r: &mut R) -> Result<()> {
+        let key = self.normalize_owned(key);
+
+        #[cfg(feature = "encryption")]
+        let buffered = self.options.dedup_values || self.options.cipher.is_some();
+        #[cfg(not(feature = "encryption"))]
+        let buffered = self.options.dedup_values;
+
+        if buffered {
+            let mut value = String::new();
+            r.read_to_string(&mut value)?;
+            return self.set(key, value);
+        }
+
+        let offset = self.offset;
+        let modified = now_millis();
+        let version = self.next_version(&key);
+
+        // a partial command is cut off again, so later commands follow the last whole one
+        let len = self.write_or_undo(|store| {
+            let buf = store.buf.as_mut().ok_or(KvsError::ReadOnly)?;
+
+            let mut counted = CountingWriter {
+                writer: &mut *buf,
+                count: 0,
+            };
+            write_streamed_set(&mut counted, &key, r, modified, version)?;
+            let len = counted.count;
+
+            store.offset += len;
+            store.unsynced += 1;
+            store.flush_buf()?;
+            Ok(len)
+        })?;
+
+        #[cfg(feature = "fault-injection")]
+        self.inject(FaultPoint::AfterWrite)?;
+
+        let position = CommandPosition {
+            segment: self.segment,
+            offset,
+            len,
+        };
+
+        // the value is not needed to index a `Set`
+        let cmd = Command::Set {
+            key,
+            value: String::new(),
+            modified,
+            version,
+        };
+        self.index_written(cmd, position)
+    }
+
+    /// Sets the value of a key to a blob shared by all keys holding an identical value.
+    fn set_dedup(&mut self, key: String, value: String) -> Result<()> {
+        let hash = dedup::hash_value(&value);
+
+        match self.blobs.position(hash) {
+            Some(blob) => {
+                // fall back to a plain value on a hash collision
+                if self.read_position(blob)?.as_ref() != Some(&value) {
+                    return self.apply(Command::Set {
+                        version: self.next_version(&key),
+                        key,
+                        value: self.seal_value(value),
+                        modified: now_millis(),
+                    });
+                }
+            }
+
+            None => {
+                let value = self.seal_value(value);
+                let blob = self.write_command(&Command::Blob { hash, value })?;
+                self.uncompacted += add_garbage(&mut self.garbage, self.blobs.insert(hash, blob));
+            }
+        }
+
+        self.apply(Command::SetRef {
+            version: self.next_version(&key),
+            key,
+            hash,
+            modified: now_millis(),
+        })
+    }
+
+    /// Writes all writes of a batch with a single flush.
+    ///
+    /// The index is only updated once every command of the batch has been written, so a
+    /// failed batch leaves the in-memory state untouched. The batch is not atomic on disk:
+    /// commands written before a crash are recovered on the next open.
+    /// With `dedup_values` enabled, writes are applied one by one.
+    pub fn write_batch(&mut self, mut batch: WriteBatch) -> Result<()> {
+        for op in &mut batch.ops {
+            let (BatchOp::Set { key, .. } | BatchOp::Remove { key }) = op;
+            *key = self.normalize_owned(mem::take(key));
+        }
+
+        self.write_indexed_batch(batch)
+    }
+
+    /// Writes a batch whose keys are taken as they are, without normalizing them again.
+    fn write_indexed_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if self.options.dedup_values {
+            for op in batch.ops {
+                match op {
+                    BatchOp::Set { key, value } => self.set_dedup(key, value)?,
+                    BatchOp::Remove { key } if self.index.contains_key(&key) => {
+                        self.remove_indexed(key)?
+                    }
+                    BatchOp::Remove { .. } => {}
+                }
+            }
+
+            return Ok(());
+        }
+
+        let mut cmds = Vec::with_capacity(batch.len());
+        // whether keys are present once the writes of the batch so far are applied
+        let mut present = HashMap::new();
+        // the versions of keys written by the batch so far
+        let mut versions = HashMap::new();
+        let mut next_version = |key: &String| {
+            *versions
+                .entry(key.clone())
+                .and_modify(|version| *version += 1)
+                .or_insert_with(|| self.next_version(key))
+        };
+
+        for op in batch.ops {
+            let cmd = match op {
+                BatchOp::Set { key, value } => {
+                    present.insert(key.clone(), true);
+                    Command::Set {
+                        version: next_version(&key),
+                        key,
+                        value: self.seal_value(value),
+                        modified: now_millis(),
+                    }
+                }
+
+                BatchOp::Remove { key } => {
+                    let exists = present
+                        .insert(key.clone(), false)
+                        .unwrap_or_else(|| self.index.contains_key(&key));
+                    if !exists {
+                        continue;
+                    }
+
+                    Command::Remove {
+                        version: next_version(&key),
+                        key,
+                    }
+                }
+            };
+
+            cmds.push(cmd);
+        }
+
+        self.write_commands(cmds)
+    }
+
+    /// Writes commands with a single flush, then applies them to the index.
+    fn write_commands(&mut self, cmds: Vec<Command>) -> Result<()> {
+        let written = self.write_or_undo(|store| {
+            let mut written = Vec::with_capacity(cmds.len());
+            for cmd in cmds {
+                let position = store.append_command(&cmd)?;
+                written.push((cmd, position));
+            }
+
+            store.flush_buf()?;
+            Ok(written)
+        })?;
+
+        for (cmd, position) in written {
+            self.count_command(&cmd);
+
+            let key = self.lru.as_ref().and(cmd.key()).map(str::to_owned);
+            self.uncompacted += index_command(
+                &mut self.index,
+                &mut self.blobs,
+                &mut self.modified,
+                &mut self.tombstones,
+                &mut self.versions,
+                &mut self.garbage,
+                cmd,
+                position,
+            );
+
+            if let Some(key) = key {
+                self.track_access(&key);
+            }
+        }
+
+        self.evict_over_budget()?;
+
+        if self.options.auto_compact && self.should_compact() {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a pipeline of operations executed in order, with all writes in one batch.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline::new(self)
+    }
+
+    /// Appends `suffix` to the value of a given key and returns the new value length.
+    ///
+    /// A missing key is treated as an empty value. The whole value is rewritten
+    /// to the log, so appending to a large value costs as much as setting it.
+    pub fn append(&mut self, key: String, suffix: &str) -> Result<usize> {
+        let mut value = self.get(key.clone())?.unwrap_or_default();
+        value.push_str(suffix);
+
+        let len = value.len();
+        self.set(key, value)?;
+
+        Ok(len)
+    }
+
+    /// Remove a given key.
+    ///
+    /// Accepts borrowed keys too; the key is only copied if it is removed, or to report
+    /// it missing.
+    pub fn remove(&mut self, key: impl AsRef<str>) -> Result<()> {
+        let key = self.normalize_key(key.as_ref());
+        if !self.index.contains_key(key.as_ref()) {
+            return Err(KvsError::KeyNotFound(key.into_owned()));
+        }
+
+        self.remove_indexed(key.into_owned())
+    }
+
+    /// Removes a key of the index, taken as it is.
+    fn remove_indexed(&mut self, key: String) -> Result<()> {
+        self.apply(Command::Remove {
+            version: self.next_version(&key),
+            key,
+        })
+    }
+
+    /// Removes the given keys with a single flush and returns whether each was present.
+    ///
+    /// Nothing is written for absent keys, including repeats of a key removed before.
+    /// Like `write_batch`, compaction runs at most once, after all keys are removed.
+    pub fn remove_many(&mut self, keys: &[String]) -> Result<Vec<bool>> {
+        let mut cmds = Vec::new();
+        let mut removed = HashSet::new();
+
+        let keys: Vec<Cow<str>> = keys.iter().map(|key| self.normalize_key(key)).collect();
+
+        let present = keys
+            .iter()
+            .map(|key| {
+                let present = self.index.contains_key(key.as_ref()) && removed.insert(key);
+                if present {
+                    cmds.push(Command::Remove {
+                        key: key.to_string(),
+                        version: self.next_version(key),
+                    });
+                }
+                present
+            })
+            .collect();
+
+        self.write_commands(cmds)?;
+
+        Ok(present)
+    }
+
+    /// Takes every key/value pair out of the store, sorted by key, leaving it empty.
+    ///
+    /// All values are read into memory before anything is removed, so this only suits
+    /// stores that fit in memory. Every key is removed in one batch before the segment
+    /// files are deleted, oldest first, so a crash midway never brings keys back. Writing
+    /// then starts over in a fresh segment.
+    pub fn drain(&mut self) -> Result<Vec<(String, String)>> {
+        if self.buf.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+
+        let mut keys: Vec<String> = self.index.keys().cloned().collect();
+        keys.sort_unstable();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in &keys {
+            if let Some(value) = self.get(key)? {
+                pairs.push((key.clone(), value));
+            }
+        }
+
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.remove(key);
+        }
+        self.write_batch(batch)?;
+
+        self.roll_segment(self.segment)?;
+        for segment in self.readers.segments() {
+            if segment == self.segment {
+                continue;
+            }
+
+            self.readers.remove(segment);
+            self.pins
+                .remove_file(segment, segment_path(&self.path, &self.options, segment))?;
+        }
+        sync_dir_if_durable(&self.path, &self.options)?;
+
+        self.index.clear();
+        self.blobs = Blobs::default();
+        self.modified.clear();
+        self.tombstones.clear();
+        self.versions.clear();
+        self.garbage.clear();
+        self.generations.clear();
+        self.replayed.clear();
+        self.uncompacted = 0;
+        if let Some(lru) = self.lru.as_mut() {
+            *lru = Lru::default();
+        }
+
+        Ok(pairs)
+    }
+
+    /// Moves the value of `from` to `to`, overwriting any value of `to`.
+    ///
+    /// Returns `false` if `from` does not exist. The value is written once more under
+    /// `to`: commands embed their key, so the bytes of the old command cannot be reused
+    /// as they are. With `dedup_values` enabled only a reference to the blob is written.
+    pub fn rename(&mut self, from: String, to: String) -> Result<bool> {
+        let from = self.normalize_owned(from);
+        let to = self.normalize_owned(to);
+
+        let position = match self.index.get(&from) {
+            None => return Ok(false),
+            Some(&position) => position,
+        };
+
+        if from == to {
+            return Ok(true);
+        }
+
+        let version = self.next_version(&to);
+        let cmd = match self.blobs.hash_of(&from) {
+            Some(hash) => Command::SetRef {
+                key: to,
+                hash,
+                modified: now_millis(),
+                version,
+            },
+            None => match self.read_position(position)? {
+                None => return Ok(false),
+                Some(value) => Command::Set {
+                    key: to,
+                    value: self.seal_value(value),
+                    modified: now_millis(),
+                    version,
+                },
+            },
+        };
+
+        // the new key is written first, so a crash in between never loses the value
+        self.apply(cmd)?;
+        self.apply(Command::Remove {
+            version: self.next_version(&from),
+            key: from,
+        })?;
+
+        Ok(true)
+    }
+
+    /// Moves every key to the key returned for it by `f`, or removes it for `None`.
+    ///
+    /// Keys are transformed in ascending order, and all values are read before anything
+    /// is written, so a key may move to a key that moves on itself. If several keys move
+    /// to the same key, or onto a key that stays, the last one transformed wins. Changes
+    /// are written as one `WriteBatch`, holding the moved values in memory until then.
+    /// `f` sees keys as they are stored and the keys it returns are normalized, so this
+    /// also moves keys written before a `key_normalizer` was set to their normalized key.
+    /// Returns the number of keys moved or removed.
+    pub fn transform_keys<F>(&mut self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&str) -> Option<String>,
+    {
+        let mut keys: Vec<String> = self.index.keys().cloned().collect();
+        keys.sort_unstable();
+
+        let mut changed = Vec::new();
+        for key in keys {
+            let new = f(&key).map(|new| self.normalize_owned(new));
+            if new.as_ref() != Some(&key) {
+                changed.push((key, new));
+            }
+        }
+
+        let targets: HashSet<&String> =
+            changed.iter().filter_map(|(_, new)| new.as_ref()).collect();
+
+        // stored keys are read and removed as they are, even if they are not normalized
+        let mut batch = WriteBatch::new();
+        for (key, new) in &changed {
+            if let Some(new) = new {
+                if let Some(value) = self.get_indexed(key)? {
+                    batch.set(new.clone(), value);
+                }
+            }
+        }
+        for (key, _) in &changed {
+            if !targets.contains(key) {
+                batch.remove(key.clone());
+            }
+        }
+
+        let transformed = changed.len();
+        self.write_indexed_batch(batch)?;
+
+        Ok(transformed)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist. Accepts borrowed keys, which
+    /// saves allocating a key just to look it up.
+    pub fn get(&mut self, key: impl AsRef<str>) -> Result<Option<String>> {
+        let key = self.normalize_key(key.as_ref());
+        self.get_indexed(&key)
+    }
+
+    /// Gets the value of a key of the index, taken as it is.
+    fn get_indexed(&mut self, key: &str) -> Result<Option<String>> {
+        let position = match self.index.get(key) {
+            None => {
+                self.counters.get(false);
+                return Ok(None);
+            }
+            Some(&position) => position,
+        };
+
+        let value = self.read_position(position)?;
+        self.counters.get(value.is_some());
+
+        if let Some(lru) = self.lru.as_mut() {
+            lru.touch(key);
+        }
+
+        Ok(value)
+    }
+
+    /// Takes a frozen view of the store that later writes and compactions leave alone.
+    ///
+    /// The snapshot copies the index and pins the segments holding its values: compaction
+    /// leaves their files in place until the last clone of the snapshot is dropped. This
+    /// only holds for compactions by this store; a snapshot of a read-only store breaks
+    /// once the writer compacts the segments it reads.
+    pub fn snapshot(&self) -> Snapshot {
+        let index = self
+            .index
+            .iter()
+            .map(|(key, &position)| {
+                let blob = self
+                    .blobs
+                    .hash_of(key)
+                    .and_then(|hash| self.blobs.position(hash));
+                (key.clone(), blob.unwrap_or(position))
+            })
+            .collect();
+
+        Snapshot::new(&self.path, &self.options, index, Arc::clone(&self.pins))
+    }
+
+    /// Gets the string value of a given key that is expected to exist.
+    ///
+    /// Fails with `KeyNotFound` instead of returning `None` if the key does not exist.
+    pub fn expect_get(&mut self, key: &str) -> Result<String> {
+        self.get(key)?
+            .ok_or_else(|| KvsError::KeyNotFound(key.to_owned()))
+    }
+
+    /// Gets the value of a given key, telling removed keys apart from ones never written.
+    ///
+    /// Compaction keeps the `Remove` command of every removed key so this survives
+    /// reopening, at the cost of removed keys taking up space until they are set again.
+    pub fn get_status(&mut self, key: String) -> Result<GetStatus> {
+        let key = self.normalize_owned(key);
+        if self.tombstones.contains_key(&key) {
+            return Ok(GetStatus::Removed);
+        }
+
+        Ok(match self.get(key)? {
+            Some(value) => GetStatus::Present(value),
+            None => GetStatus::NeverWritten,
+        })
+    }
+
+    /// Returns the number of keys, without reading any value.
+    pub(crate) fn key_count(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Gets the value of a given key along with its version.
+    ///
+    /// The version counts the writes of the key, removals included, and is recorded in
+    /// the log, so it only ever grows, across compactions and reopening too. Keys never
+    /// written are at version 0, as are keys last written by versions of kvs that did not
+    /// record it. Pass the version to `set_if_version` to write only if nobody wrote the
+    /// key in between. `drain` starts versions over.
+    ///
+    /// Versions are kept in memory for every key in the index and every removed key whose
+    /// `Remove` command is still kept, see `get_status`, so removed keys cost memory until
+    /// they are set again. Compaction drops the versions of any other key.
+    pub fn get_versioned(&mut self, key: String) -> Result<(Option<String>, u64)> {
+        let key = self.normalize_owned(key);
+        let value = self.get(&key)?;
+        let version = self.versions.get(&key).copied().unwrap_or(0);
+
+        Ok((value, version))
+    }
+
+    /// Sets the value of a key only if it is still at `expected_version`.
+    ///
+    /// Returns whether the value was set. See `get_versioned`.
+    pub fn set_if_version(
+        &mut self,
+        key: String,
+        value: String,
+        expected_version: u64,
+    ) -> Result<bool> {
+        let key = self.normalize_owned(key);
+        if self.versions.get(&key).copied().unwrap_or(0) != expected_version {
+            return Ok(false);
+        }
+
+        self.set(key, value)?;
+
+        Ok(true)
+    }
+
+    /// Returns `key` as stored, see `KvStoreOptions::key_normalizer`.
+    pub(crate) fn normalize_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        self.options.normalize_key(key)
+    }
+
+    /// Like `normalize_key`, for keys passed by value.
+    pub(crate) fn normalize_owned(&self, key: String) -> String {
+        if self.options.key_normalizer.is_none() {
+            return key;
+        }
+
+        self.options.normalize_key(&key).into_owned()
+    }
+
+    /// Returns the version the next write of a key gets.
+    fn next_version(&self, key: &str) -> u64 {
+        self.versions.get(key).map_or(1, |version| version + 1)
+    }
+
+    /// Returns whether a given key exists, without reading its value.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(self.normalize_key(key).as_ref())
+    }
+
+    /// Returns whether a given key exists, comparing it against every key of the index.
+    ///
+    /// This is a best-effort constant-time check: unlike `contains_key` it does not hash
+    /// the key into a bucket, and each comparison goes through all bytes without stopping at
+    /// the first difference, so the time taken does not tell which key matched or how much
+    /// of it did. It still tells the number of keys and does depend on the lengths of the
+    /// keys compared, and nothing stops the compiler or the hardware from taking shortcuts.
+    /// Its cost grows with the number of keys.
+    pub fn contains_key_ct(&self, key: &str) -> bool {
+        let key = self.normalize_key(key);
+        let found = self.index.keys().fold(0u8, |found, candidate| {
+            found | ct_eq(candidate.as_bytes(), key.as_bytes())
+        });
+
+        std::hint::black_box(found) == 1
+    }
+
+    /// Returns the length in bytes of the record holding the value of a given key.
+    ///
+    /// This is read from the index, without touching the log. It is the length of the
+    /// whole JSON command, so it includes the framing, the key and any escaping on top of
+    /// the value itself; for deduplicated keys it is the length of the shared blob. Use
+    /// `with_value` to get the exact decoded length.
+    pub fn value_len(&self, key: &str) -> Option<u64> {
+        let key = self.normalize_key(key);
+        let position = *self.index.get(key.as_ref())?;
+
+        let blob = self
+            .blobs
+            .hash_of(&key)
+            .and_then(|hash| self.blobs.position(hash));
+
+        Some(blob.unwrap_or(position).len)
+    }
+
+    /// Returns when a given key was last written.
+    ///
+    /// Keys written by versions that did not record write times report the Unix epoch.
+    pub fn last_modified(&self, key: &str) -> Option<SystemTime> {
+        self.modified.get(self.normalize_key(key).as_ref()).copied()
+    }
+
+    /// Returns the key/value pairs of keys last written at or after `since`, sorted by key.
+    ///
+    /// Write times are recorded in the log, so this works the same after reopening. Keys
+    /// written by versions that did not record write times only match `UNIX_EPOCH`.
+    pub fn scan_modified_since(&mut self, since: SystemTime) -> Result<Vec<(String, String)>> {
+        let mut keys: Vec<String> = self
+            .modified
+            .iter()
+            .filter(|&(_, &modified)| modified >= since)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort_unstable();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            if let Some(value) = self.get(&key)? {
+                pairs.push((key, value));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    /// Calls `f` with a borrow of the value of a given key and returns its result.
+    ///
+    /// The value is read into a buffer owned by the store and, unless it contains JSON
+    /// escapes, decoded without allocating, which saves copying large values that are only
+    /// inspected. Returns `None` if the given key does not exist. Counts as a `get`, and
+    /// is subject to `read_timeout` like one.
+    pub fn with_value<F, T>(&mut self, key: String, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&str) -> T,
+    {
+        let key = self.normalize_owned(key);
+        let position = match self.value_position(&key) {
+            None => {
+                self.counters.get(false);
+                return Ok(None);
+            }
+            Some(position) => position,
+        };
+
+        match self.read_timeout_for(position.segment) {
+            Some(timeout) => self.scratch = self.read_record_timed(position, timeout)?,
+            None => {
+                let reader = self.readers.get_or_open(position.segment)?;
+                seek_reader(reader, position.offset)?;
+
+                self.scratch.clear();
+                reader.take(position.len).read_to_end(&mut self.scratch)?;
+            }
+        }
+
+        let res = match serde_json::from_slice(&self.scratch)? {
+            ValueCommand::Set { value } | ValueCommand::Blob { value } => {
+                f(&self.open_value(&value)?)
+            }
+        };
+        self.counters.get(true);
+
+        if let Some(lru) = self.lru.as_mut() {
+            lru.touch(&key);
+        }
+
+        Ok(Some(res))
+    }
+
+    /// Returns the position of the command holding the value of `key`.
+    ///
+    /// Values of deduplicated keys live in their blob.
+    fn value_position(&self, key: &str) -> Option<CommandPosition> {
+        let &position = self.index.get(key)?;
+
+        match self.blobs.hash_of(key) {
+            Some(hash) => self.blobs.position(hash),
+            None => Some(position),
+        }
+    }
+
+    /// Returns a reader over the value of a given key, read from the log as it is consumed.
+    ///
+    /// The reader has a file handle of its own, bounded to the record holding the value,
+    /// and unescapes the value as it goes, so large values can be fed to a streaming parser
+    /// like `serde_json::Deserializer::from_reader` without being held in memory as a
+    /// whole. Compaction does not disturb a reader once it is returned. With encryption
+    /// enabled the value is decrypted into memory first, and with `read_timeout` set it is
+    /// read into memory on a thread of its own first, like `get` does. Returns `None` if
+    /// the given key does not exist.
+    pub fn get_reader(&self, key: &str) -> Result<Option<impl Read>> {
+        let key = self.normalize_key(key);
+        let position = match self.value_position(&key) {
+            None => {
+                self.counters.get(false);
+                return Ok(None);
+            }
+            Some(position) => position,
+        };
+
+        if let Some(timeout) = self.read_timeout_for(position.segment) {
+            let record = self.read_record_timed(position, timeout)?;
+            let value = match serde_json::from_slice(&record)? {
+                ValueCommand::Set { value } | ValueCommand::Blob { value } => {
+                    self.open_value(&value)?.into_owned()
+                }
+            };
+
+            self.counters.get(true);
+            return Ok(Some(ValueReader::Buffered(io::Cursor::new(
+                value.into_bytes(),
+            ))));
+        }
+
+        let mut file = File::open(segment_path(&self.path, &self.options, position.segment))?;
+        file.seek(SeekFrom::Start(position.offset))?;
+        let record =
+            BufReader::with_capacity(self.options.read_buffer_size, file.take(position.len));
+
+        #[cfg(feature = "encryption")]
+        if self.options.cipher.is_some() {
+            let mut value = String::new();
+            ValueReader::new(record)?.read_to_string(&mut value)?;
+            let value = self.open_value(&value)?.into_owned();
+
+            self.counters.get(true);
+            return Ok(Some(ValueReader::Buffered(io::Cursor::new(
+                value.into_bytes(),
+            ))));
+        }
+
+        self.counters.get(true);
+        Ok(Some(ValueReader::new(record)?))
+    }
+
+    /// Returns the lexicographically smallest key, without reading any value.
+    ///
+    /// The index is a hash map, so this looks at every key; a sorted index would answer
+    /// in O(log n).
+    pub fn first_key(&self) -> Option<&String> {
+        self.index.keys().min()
+    }
+
+    /// Returns the lexicographically largest key, without reading any value.
+    ///
+    /// Like `first_key`, this looks at every key.
+    pub fn last_key(&self) -> Option<&String> {
+        self.index.keys().max()
+    }
+
+    /// Sets the value of a string key within a namespace.
+    ///
+    /// Namespaces partition the key space: the same key may hold different values
+    /// in different namespaces. The empty namespace is the plain key space used by `set`.
+    /// Namespaces must not contain `'\0'`, and neither may keys of the empty namespace,
+    /// or they would be mistaken for keys of another namespace; both fail with
+    /// `InvalidNamespace`.
+    pub fn set_ns(&mut self, ns: &str, key: String, value: String) -> Result<()> {
+        self.set(namespaced_key(ns, key)?, value)
+    }
+
+    /// Gets the string value of a given string key within a namespace.
+    pub fn get_ns(&mut self, ns: &str, key: String) -> Result<Option<String>> {
+        self.get(namespaced_key(ns, key)?)
+    }
+
+    /// Removes a given key within a namespace.
+    pub fn remove_ns(&mut self, ns: &str, key: String) -> Result<()> {
+        self.remove(namespaced_key(ns, key)?)
+    }
+
+    /// Returns the keys of a namespace, without their namespace.
+    pub fn keys_ns(&self, ns: &str) -> Result<Vec<String>> {
+        check_namespace(ns)?;
+
+        Ok(self
+            .index
+            .keys()
+            .filter_map(|key| split_namespaced_key(key))
+            .filter(|&(key_ns, _)| key_ns == ns)
+            .map(|(_, key)| key.to_owned())
+            .collect())
+    }
+
+    /// Returns the key/value pairs of a namespace, without their namespace.
+    pub fn scan_ns(&mut self, ns: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+
+        for key in self.keys_ns(ns)? {
+            if let Some(value) = self.get_ns(ns, key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    /// Returns the number of keys per prefix, the part of each key before `separator`.
+    ///
+    /// Only the index is looked at, no values are read. Keys without the separator are
+    /// counted under the empty prefix.
+    pub fn count_by_prefix(&self, separator: char) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+
+        for key in self.index.keys() {
+            let prefix = key.split_once(separator).map_or("", |(prefix, _)| prefix);
+            *counts.entry(prefix.to_owned()).or_insert(0) += 1;
+        }
+
+        counts
+    }
+
+    /// Returns the keys matching a glob pattern in ascending order, without reading values.
+    ///
+    /// `?` matches any character, `*` any sequence of characters, `[abc]` and `[a-z]` any
+    /// character of a class and `[!abc]` any character not in it, e.g. `session:*:active`.
+    /// Fails with `InvalidPattern` on an unclosed or empty class.
+    pub fn scan_glob(&self, pattern: &str) -> Result<Vec<String>> {
+        let pattern = Pattern::new(pattern)?;
+
+        let mut keys: Vec<String> = self
+            .index
+            .keys()
+            .filter(|key| pattern.matches(key))
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+
+        Ok(keys)
+    }
+
+    /// Inserts a known command position into the index without writing to the log.
+    ///
+    /// This is meant for restoring an index from an external snapshot; the caller is
+    /// responsible for the position pointing at a `Set` command of `key`. The key is
+    /// normalized like the keys of writes, see `KvStoreOptions::key_normalizer`.
+    /// Fails with `MissingSegment` if the segment is not part of the store.
+    pub fn index_insert_raw(
+        &mut self,
+        key: String,
+        segment: u64,
+        offset: u64,
+        len: u64,
+    ) -> Result<()> {
+        if !self.readers.contains(segment) {
+            return Err(KvsError::MissingSegment(segment));
+        }
+
+        let key = self.normalize_owned(key);
+
+        if let Some(tombstone) = self.tombstones.remove(&key) {
+            self.uncompacted += add_garbage(&mut self.garbage, Some(tombstone));
+        }
+
+        if let Some(position) = self.index.insert(
+            key,
+            CommandPosition {
+                segment,
+                offset,
+                len,
+            },
+        ) {
+            self.uncompacted += add_garbage(&mut self.garbage, Some(position));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the value of the record at an explicit position, without the key index.
+    ///
+    /// This is for external indexes built with `set_and_locate`, and tools that know a
+    /// position from `dump_segment`. Returns `None` if the record is a removal. Fails with
+    /// `MissingSegment` if the segment is not part of the store, and with `Corruption` if
+    /// the position reaches past the end of the segment or does not hold exactly one record.
+    pub fn get_at(&self, segment: u64, offset: u64, len: u64) -> Result<Option<String>> {
+        if !self.readers.contains(segment) {
+            return Err(KvsError::MissingSegment(segment));
+        }
+
+        let mut file = File::open(segment_path(&self.path, &self.options, segment))?;
+        let segment_len = file.metadata()?.len();
+        if offset.checked_add(len).is_none_or(|end| end > segment_len) {
+            return Err(KvsError::Corruption(segment, offset));
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len as usize];
+        file.read_exact(&mut bytes)?;
+
+        let cmd =
+            serde_json::from_slice(&bytes).map_err(|_| KvsError::Corruption(segment, offset))?;
+
+        match cmd {
+            Command::Set { value, .. } | Command::Blob { value, .. } => {
+                Ok(Some(self.open_value(&value)?.into_owned()))
+            }
+
+            Command::SetRef { hash, .. } => match self.blobs.position(hash) {
+                Some(blob) => self.get_at(blob.segment, blob.offset, blob.len),
+                None => Ok(None),
+            },
+
+            Command::Remove { .. } => Ok(None),
+        }
+    }
+
+    /// Returns every command of a segment with its offset, including stale ones.
+    ///
+    /// Fails with `MissingSegment` if the segment is not part of the store.
+    pub fn dump_segment(&self, segment: u64) -> Result<Vec<(u64, PublicCommand)>> {
+        if !self.readers.contains(segment) {
+            return Err(KvsError::MissingSegment(segment));
+        }
+
+        let mut reader = segment_reader(&self.path, &self.options, segment)?;
+
+        CommandReader::new(&mut reader)
+            .map(|res| res.map(|(offset, _, cmd)| (offset, cmd.into())))
+            .collect()
+    }
+
+    /// Returns every write of a given key still on disk, in log order.
+    ///
+    /// Each write is returned as its segment, its offset and the value it set, or `None`
+    /// for removes. Overwritten values are only found until compaction reclaims them, and
+    /// compaction moves live writes to newer segments, so log order is only the order the
+    /// writes were made in for segments that were not compacted. Every segment is read
+    /// through, which makes this meant for debugging rather than regular use.
+    pub fn history(&self, key: &str) -> Result<Vec<(u64, u64, Option<String>)>> {
+        let key = self.normalize_key(key);
+        let mut writes = Vec::new();
+        let mut blobs = HashMap::new();
+
+        for segment in self.readers.segments() {
+            let mut reader = segment_reader(&self.path, &self.options, segment)?;
+
+            for res in CommandReader::new(&mut reader) {
+                let (offset, _, cmd) = match res {
+                    // a torn write at the tail of the segment
+                    Err(KvsError::Serde(err)) if err.is_eof() => break,
+                    res => res?,
+                };
+
+                match cmd {
+                    Command::Blob { hash, value } => {
+                        blobs.insert(hash, value);
+                    }
+                    Command::Set {
+                        key: found, value, ..
+                    } if found == key => {
+                        writes.push((segment, offset, KeyWrite::Value(value)));
+                    }
+                    Command::SetRef {
+                        key: found, hash, ..
+                    } if found == key => {
+                        writes.push((segment, offset, KeyWrite::Blob(hash)));
+                    }
+                    Command::Remove { key: found, .. } if found == key => {
+                        writes.push((segment, offset, KeyWrite::Remove));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // blobs may follow the references to them once compaction moved them
+        writes
+            .into_iter()
+            .map(|(segment, offset, write)| {
+                let value = match write {
+                    KeyWrite::Value(value) => Some(value),
+                    KeyWrite::Blob(hash) => blobs.get(&hash).cloned(),
+                    KeyWrite::Remove => None,
+                };
+                let value = match value {
+                    Some(value) => Some(self.open_value(&value)?.into_owned()),
+                    None => None,
+                };
+
+                Ok((segment, offset, value))
+            })
+            .collect()
+    }
+
+    /// Returns a serializable copy of the index, sorted by key.
+    pub fn index_snapshot(&self) -> BTreeMap<String, CommandPositionPublic> {
+        self.index
+            .iter()
+            .map(|(key, &position)| (key.clone(), position.into()))
+            .collect()
+    }
+
+    /// Checks that every key of the index points at a command writing it on disk.
+    ///
+    /// Each command is re-read from its segment and compared with its position, which is
+    /// how a compaction or recovery can be confirmed not to have corrupted anything.
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let mut keys: Vec<_> = self.index.iter().collect();
+        keys.sort_unstable_by_key(|&(key, _)| key);
+
+        let mut report = VerifyReport::default();
+
+        for (key, position) in keys {
+            report.checked += 1;
+
+            let reader = match self.readers.get_mut(position.segment)? {
+                None => {
+                    report.missing.push(key.clone());
+                    continue;
+                }
+                Some(reader) => reader,
+            };
+
+            seek_reader(reader, position.offset)?;
+
+            let mut counted = CountingReader { reader, count: 0 };
+            let res =
+                Command::deserialize(&mut serde_json::Deserializer::from_reader(&mut counted));
+
+            match res {
+                Err(_) => report.missing.push(key.clone()),
+                Ok(Command::Set { key: found, .. }) | Ok(Command::SetRef { key: found, .. })
+                    if found == *key =>
+                {
+                    if counted.count != position.len {
+                        report.wrong_length.push(key.clone());
+                    }
+                }
+                Ok(_) => report.mismatched.push(key.clone()),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Writes a copy of every segment file to `w`, each preceded by its number and length.
+    ///
+    /// This is much faster than re-encoding every value for large stores. The active
+    /// segment is flushed and included at its current length. See `restore_physical`.
+    pub fn physical_backup<W: Write>(&mut self, w: &mut W) -> Result<BackupManifest> {
+        if let Some(buf) = self.buf.as_mut() {
+            buf.flush()?;
+        }
+
+        let segments = self.readers.segments();
+
+        let mut manifest = BackupManifest::default();
+        w.write_all(BACKUP_MAGIC)?;
+
+        for segment in segments {
+            let file = File::open(segment_path(&self.path, &self.options, segment))?;
+            let len = file.metadata()?.len();
+
+            w.write_all(&segment.to_le_bytes())?;
+            w.write_all(&len.to_le_bytes())?;
+
+            // the writer may have appended since, only the length in the header is copied
+            let copied = io::copy(&mut file.take(len), w)?;
+            if copied != len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+
+            manifest.segments.push((segment, len));
+        }
+
+        w.flush()?;
+
+        Ok(manifest)
+    }
+
+    /// Recreates the segment files of a store from a `physical_backup` in directory `path`.
+    ///
+    /// The segments are named according to `options`, which must match those of the
+    /// backed up store. Fails without overwriting anything if a segment file already exists.
+    pub fn restore_physical<R: Read>(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+        r: &mut R,
+    ) -> Result<BackupManifest> {
+        let path: PathBuf = path.into();
+        create_store_dir(&path)?;
+
+        let mut magic = [0; BACKUP_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != *BACKUP_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a kvs backup").into());
+        }
+
+        let mut manifest = BackupManifest::default();
+
+        while let Some(segment) = read_u64(r)? {
+            let len = read_u64(r)?.ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(segment_path(&path, options, segment))?;
+
+            let copied = io::copy(&mut r.take(len), &mut file)?;
+            if copied != len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            file.sync_all()?;
+
+            manifest.segments.push((segment, len));
+        }
+
+        sync_dir_if_durable(&path, options)?;
+
+        Ok(manifest)
+    }
+
+    /// Creates a replica in directory `path` from a base snapshot and delta segments.
+    ///
+    /// The base snapshot is a `physical_backup` of the source store, restored as with
+    /// `restore_physical`. The deltas are segment files of the source written after the
+    /// snapshot, e.g. those sealed since, in the order they were written. Each is copied
+    /// into a new segment after all others, so replaying the store applies it on top of
+    /// the snapshot and the deltas before it: newer writes win. Fails without overwriting
+    /// anything if a segment file already exists.
+    pub fn open_replica<R: Read>(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+        base: &mut R,
+        deltas: &[PathBuf],
+    ) -> Result<KvStore> {
+        let path: PathBuf = path.into();
+        KvStore::restore_physical(&path, &options, base)?;
+
+        let mut segment = 0;
+        for delta in deltas {
+            let (next, mut buf) = create_next_segment(&path, &options, segment)?;
+            io::copy(&mut File::open(delta)?, &mut buf)?;
+            buf.flush()?;
+            if options.syncs_segments() {
+                buf.get_ref().sync_data()?;
+            }
+
+            segment = next;
+        }
+        sync_dir_if_durable(&path, &options)?;
+
+        KvStore::open_with_options(path, options)
+    }
+
+    /// Returns the segments of the store in the order `open` replays them, ascending.
+    ///
+    /// Replaying the segments in this order rebuilds the current state: a record in a
+    /// higher-numbered segment always supersedes a record of the same key in a lower one.
+    /// New segments, whether the next active segment or the output of a compaction, are
+    /// numbered after every segment file in the directory, including ones left behind by
+    /// a crashed compaction. Compaction only moves live records, and only to such a new
+    /// segment or the active one, so no record of the same key is newer than where they
+    /// land; the stale records it leaves behind in kept or cold segments are all older.
+    pub fn segment_order(&self) -> Vec<u64> {
+        self.readers.segments()
+    }
+
+    /// Returns the bytes of stale commands in each segment that has any.
+    ///
+    /// Unlike the total that triggers compaction, this includes garbage left behind in
+    /// segments kept by `keep_recent_segments`, so it can guide `compact_segment`.
+    pub fn segment_garbage(&self) -> HashMap<u64, u64> {
+        self.garbage.clone()
+    }
+
+    /// Returns whether enough stale data has built up for a compaction to be worthwhile.
+    ///
+    /// Writes compact automatically under exactly this condition, unless `auto_compact`
+    /// is disabled.
+    pub fn should_compact(&self) -> bool {
+        self.uncompacted > self.compaction_threshold
+    }
+
+    /// Sets how many bytes of stale data may build up before writes compact, 1 MB by default.
+    ///
+    /// This can be changed at any time, e.g. raised during a bulk load and lowered again
+    /// afterwards. If the store is over a lowered threshold, the next write compacts.
+    /// The threshold is a policy of the open store only: nothing of it is written to the
+    /// log, so a store may be reopened with any threshold.
+    pub fn set_compaction_threshold(&mut self, bytes: u64) {
+        self.compaction_threshold = bytes;
+    }
+
+    /// Estimates the effect of compacting the storage without touching any files.
+    ///
+    /// Only the segments `compact` would rewrite are counted, so segments kept by
+    /// `keep_recent_segments` and cold ones left by `cold_after_compactions` are left out,
+    /// and with `CompactionPolicy::SizeTiered` only the tier to merge is counted. If the
+    /// size of a segment cannot be read to tell its tier, all tiers are counted instead.
+    pub fn estimate_compaction(&self) -> CompactionEstimate {
+        let segments = self
+            .segments_to_compact()
+            .unwrap_or_else(|_| self.compaction_candidates());
+        let involved = |segment: &u64| segments.binary_search(segment).is_ok();
+
+        CompactionEstimate {
+            reclaimable_bytes: self
+                .garbage
+                .iter()
+                .filter(|(segment, _)| involved(segment))
+                .map(|(_, garbage)| garbage)
+                .sum(),
+            live_bytes: self
+                .index
+                .values()
+                .chain(self.tombstones.values())
+                .copied()
+                .chain(self.blobs.positions())
+                .filter(|position| involved(&position.segment))
+                .map(|position| position.len)
+                .sum(),
+            segments_involved: segments.len(),
+        }
+    }
+
+    /// Returns the segments the next compaction rewrites, in ascending order.
+    ///
+    /// With `CompactionPolicy::SizeTiered` these are the segments of a single tier among
+    /// the candidates, see `tier_to_merge`.
+    fn segments_to_compact(&self) -> Result<Vec<u64>> {
+        let candidates = self.compaction_candidates();
+
+        match self.options.compaction_policy {
+            CompactionPolicy::SizeTiered if !self.options.dedup_values => {
+                self.tier_to_merge(&candidates)
+            }
+            _ => Ok(candidates),
+        }
+    }
+
+    /// Returns the segments compaction may rewrite, in ascending order.
+    ///
+    /// These are all segments but the most recent ones kept by `keep_recent_segments` and
+    /// the cold ones, see `is_cold`.
+    fn compaction_candidates(&self) -> Vec<u64> {
+        let mut segments = self.readers.segments();
+
+        let keep = if self.options.dedup_values {
+            0
+        } else {
+            self.options.keep_recent_segments.min(segments.len())
+        };
+        segments.truncate(segments.len() - keep);
+        segments.retain(|&segment| !self.is_cold(segment));
+
+        segments
+    }
+
+    /// Compacts the storage and returns what it rewrote
+    ///
+    /// Every segment but the most recent ones kept by `keep_recent_segments` and the cold
+    /// ones left by `cold_after_compactions` is rewritten into a single compacted segment
+    /// holding only its live commands. With `CompactionPolicy::SizeTiered` only the
+    /// segments of the lowest full tier among those are, see `KvStoreOptions::compaction_policy`.
+    /// Memory use is bounded regardless of the size of the store: commands are copied
+    /// through a single buffer of `compaction_io_buffer` bytes and their positions are
+    /// updated in place, so nothing grows with the number of keys or the size of values.
+    /// Compaction borrows the store mutably, like every write, so a store shared between
+    /// threads behind a lock never runs two compactions at once, and writes racing with a
+    /// compaction wait for it and then go to the new active segment.
+    pub fn compact(&mut self) -> Result<CompactionResult> {
+        if self.buf.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+
+        let compacted = self.segments_to_compact()?;
+
+        if compacted.is_empty() {
+            // only seal the active segment, so it ages out of the kept ones or joins a tier
+            self.roll_segment(self.segment)?;
+            self.uncompacted = 0;
+            return Ok(CompactionResult::default());
+        }
+
+        let mut result = CompactionResult::default();
+        for &segment in &compacted {
+            result.bytes_before +=
+                fs::metadata(segment_path(&self.path, &self.options, segment))?.len();
+        }
+
+        let mut compact_offset = 0;
+        // never reuse a segment number that exists on disk, e.g. left over by a crashed compaction
+        let (compact_segment, segment_buf) =
+            create_next_segment(&self.path, &self.options, self.segment)?;
+        // with atomic renames the segment stays empty until the copy is renamed over it
+        let scratch = self.path.join(COMPACTION_SCRATCH);
+        let atomic = self.renames_atomically();
+        let mut compact_buf = if atomic {
+            drop(segment_buf);
+            scratch_segment(&scratch)?
+        } else {
+            segment_buf
+        };
+
+        self.blobs.retain_referenced();
+
+        let mut copy_buf = vec![0; self.options.compaction_io_buffer];
+
+        // the index only moves to the copies once all of them are written, so a failed
+        // copy leaves the store reading from the segments it has
+        let copied = (|| -> Result<HashMap<(u64, u64), CommandPosition>> {
+            let mut copied = HashMap::new();
+
+            // blobs go first, so they are known by the time their references are replayed
+            let blobs = in_log_order(self.blobs.slots_mut().map(|(_, slot)| slot.position));
+            let positions =
+                in_log_order(self.index.values().chain(self.tombstones.values()).copied());
+
+            for position in blobs.into_iter().chain(positions) {
+                // keys written to the kept and cold segments stay where they are
+                if compacted.binary_search(&position.segment).is_err() {
+                    continue;
+                }
+
+                let copy = copy_command(
+                    &mut self.readers,
+                    position,
+                    &mut compact_buf,
+                    compact_segment,
+                    &mut compact_offset,
+                    &mut copy_buf,
+                )?;
+                copied.insert((position.segment, position.offset), copy);
+            }
+
+            compact_buf.flush()?;
+            if self.options.syncs_segments() {
+                // the compacted segment must be on disk before the segments it replaces go
+                compact_buf.get_ref().sync_data()?;
+            }
+            if atomic {
+                fs::rename(
+                    &scratch,
+                    segment_path(&self.path, &self.options, compact_segment),
+                )?;
+            }
+
+            Ok(copied)
+        })();
+
+        let copied = match copied {
+            Ok(copied) => copied,
+            Err(err) => {
+                drop(compact_buf);
+                if atomic {
+                    let _ = fs::remove_file(&scratch);
+                }
+                let _ = fs::remove_file(segment_path(&self.path, &self.options, compact_segment));
+                return Err(err.on_write());
+            }
+        };
+        result.records_rewritten = copied.len() as u64;
+        self.relocate(&copied);
+
+        // whatever garbage is left behind in kept segments must not trigger another
+        // compaction right away, while a failed compaction leaves the count as it was
+        self.uncompacted = 0;
+
+        #[cfg(feature = "fault-injection")]
+        self.inject(FaultPoint::AfterCompactionCopy)?;
+
+        self.readers.insert(
+            compact_segment,
+            segment_reader(&self.path, &self.options, compact_segment)?,
+        );
+        self.roll_segment(compact_segment)?;
+
+        let generation = compacted
+            .iter()
+            .filter_map(|segment| self.generations.get(segment))
+            .max()
+            .map_or(1, |generation| generation + 1);
+        self.generations.insert(compact_segment, generation);
+
+        // remove stale log files.
+        for segment in compacted {
+            self.garbage.remove(&segment);
+            self.generations.remove(&segment);
+            self.readers.remove(segment);
+            self.pins
+                .remove_file(segment, segment_path(&self.path, &self.options, segment))?;
+        }
+
+        // only keys with a position or a tombstone left still need their version
+        let (index, tombstones) = (&self.index, &self.tombstones);
+        self.versions
+            .retain(|key, _| index.contains_key(key) || tombstones.contains_key(key));
+
+        self.counters.compaction();
+        sync_dir_if_durable(&self.path, &self.options)?;
+
+        result.bytes_after = compact_offset;
+        result.reclaimed = result.bytes_before.saturating_sub(result.bytes_after);
+
+        Ok(result)
+    }
+
+    /// Writes the live records of the store into a single segment of a new store in `path`.
+    ///
+    /// Unlike `compact`, the store itself is left alone. The copy holds each live key once,
+    /// in the native format, ready to be opened with the same options, e.g. to seed another
+    /// environment. Removed keys leave no tombstones behind in it. Fails without writing
+    /// anything if `path` already holds segments.
+    pub fn compact_into(&mut self, path: impl Into<PathBuf>) -> Result<()> {
+        let path: PathBuf = path.into();
+        create_store_dir(&path)?;
+
+        if !sorted_segments(&path, &self.options)?.is_empty() {
+            let err = io::Error::new(io::ErrorKind::AlreadyExists, "directory holds segments");
+            return Err(err.into());
+        }
+
+        let segment = 1;
+        let mut buf = new_segment(&path, &self.options, segment)?;
+        let mut copy_buf = vec![0; self.options.compaction_io_buffer];
+
+        let res = (|| -> Result<()> {
+            let mut offset = 0;
+
+            // blobs go first, so they are known by the time their references are replayed
+            let hashes: HashSet<u64> = self
+                .index
+                .keys()
+                .filter_map(|key| self.blobs.hash_of(key))
+                .collect();
+            let blobs = in_log_order(hashes.iter().filter_map(|&hash| self.blobs.position(hash)));
+            let positions = in_log_order(self.index.values().copied());
+
+            for position in blobs.into_iter().chain(positions) {
+                copy_command(
+                    &mut self.readers,
+                    position,
+                    &mut buf,
+                    segment,
+                    &mut offset,
+                    &mut copy_buf,
+                )?;
+            }
+
+            buf.flush()?;
+            if self.options.syncs_segments() {
+                buf.get_ref().sync_data()?;
+            }
+
+            Ok(())
+        })();
+
+        if res.is_err() {
+            drop(buf);
+            let _ = fs::remove_file(segment_path(&path, &self.options, segment));
+        }
+        res?;
+
+        sync_dir_if_durable(&path, &self.options)
+    }
+
+    /// Moves the live commands of a single segment to the active segment and removes it.
+    ///
+    /// This compacts a store piece by piece instead of rewriting it as a whole. Compacting
+    /// the active segment seals it first. Fails with `MissingSegment` if the segment is not
+    /// part of the store.
+    pub fn compact_segment(&mut self, segment: u64) -> Result<()> {
+        if self.buf.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        if !self.readers.contains(segment) {
+            return Err(KvsError::MissingSegment(segment));
+        }
+
+        if segment == self.segment {
+            self.roll_segment(self.segment)?;
+        }
+
+        let mut copy_buf = vec![0; self.options.compaction_io_buffer];
+
+        // the index only moves to the copies once all of them are written
+        let copied = self.write_or_undo(|store| {
+            let buf = store.buf.as_mut().ok_or(KvsError::ReadOnly)?;
+            let mut copied = HashMap::new();
+
+            // blobs go first, so they are known by the time their references are replayed
+            let blobs = store.blobs.slots_mut().map(|(_, slot)| slot.position);
+            let positions = store
+                .index
+                .values()
+                .chain(store.tombstones.values())
+                .copied();
+
+            for position in blobs.chain(positions) {
+                if position.segment != segment {
+                    continue;
+                }
+
+                let copy = copy_command(
+                    &mut store.readers,
+                    position,
+                    buf,
+                    store.segment,
+                    &mut store.offset,
+                    &mut copy_buf,
+                )?;
+                copied.insert((position.segment, position.offset), copy);
+            }
+
+            store.flush_buf()?;
+            if let Durability::FsyncEvery(_) = store.options.durability {
+                // the copies must be on disk before the segment they replace goes
+                store.sync_active()?;
+            }
+
+            Ok(copied)
+        })?;
+        self.relocate(&copied);
+
+        #[cfg(feature = "fault-injection")]
+        self.inject(FaultPoint::AfterCompactionCopy)?;
+
+        self.readers.remove(segment);
+        self.pins
+            .remove_file(segment, segment_path(&self.path, &self.options, segment))?;
+        sync_dir_if_durable(&self.path, &self.options)?;
+
+        let garbage = self.garbage.remove(&segment).unwrap_or(0);
+        self.uncompacted = self.uncompacted.saturating_sub(garbage);
+        self.generations.remove(&segment);
+        self.counters.compaction();
+
+        Ok(())
+    }
+
+    /// Points the index at the copies of moved commands, keyed by their old segment and offset.
+    fn relocate(&mut self, copied: &HashMap<(u64, u64), CommandPosition>) {
+        let blobs = self.blobs.slots_mut().map(|(_, slot)| &mut slot.position);
+        let positions = self.index.values_mut().chain(self.tombstones.values_mut());
+
+        for position in blobs.chain(positions) {
+            if let Some(&copy) = copied.get(&(position.segment, position.offset)) {
+                *position = copy;
+            }
+        }
+    }
+
+    /// Returns the sealed segments of the lowest tier that is full, or none if no tier is.
+    ///
+    /// Segments are tiered by size, each tier holding segments up to `TIER_FANOUT` times
+    /// larger than the one below, so merging a full tier makes a segment of the next one.
+    fn tier_to_merge(&self, candidates: &[u64]) -> Result<Vec<u64>> {
+        let mut tiers: BTreeMap<u32, Vec<u64>> = BTreeMap::new();
+
+        for &segment in candidates {
+            if segment == self.segment {
+                continue;
+            }
+
+            let len = fs::metadata(segment_path(&self.path, &self.options, segment))?.len();
+            let tier = (len / TIER_BASE_BYTES)
+                .checked_ilog(TIER_FANOUT as u64)
+                .map_or(0, |tier| tier + 1);
+            tiers.entry(tier).or_default().push(segment);
+        }
+
+        Ok(tiers
+            .into_values()
+            .find(|segments| segments.len() >= TIER_FANOUT)
+            .unwrap_or_default())
+    }
+
+    /// Returns whether compaction leaves a segment in place as cold data.
+    ///
+    /// A segment is cold once its commands went through `cold_after_compactions`
+    /// compactions and none of them went stale since.
+    fn is_cold(&self, segment: u64) -> bool {
+        let cold_after = match self.options.cold_after_compactions {
+            None => return false,
+            Some(cold_after) => cold_after,
+        };
+
+        self.generations.get(&segment).copied().unwrap_or(0) >= cold_after
+            && self.garbage.get(&segment).copied().unwrap_or(0) == 0
+    }
+
+    /// Seals the active segment and continues writing to a new one after `after`.
+    fn roll_segment(&mut self, after: u64) -> Result<()> {
+        match self.options.durability {
+            // writes short of the next sync must not be left unsynced in the sealed segment
+            Durability::FsyncEvery(_) => self.sync_active()?,
+            _ => {
+                if let Some(buf) = self.buf.as_mut() {
+                    buf.flush()?;
+                }
+            }
+        }
+
+        let (segment, buf) = create_next_segment(&self.path, &self.options, after)?;
+        if let Some(committer) = &self.committer {
+            committer.switch(buf.get_ref().try_clone()?)?;
+        }
+        self.segment = segment;
+        self.offset = 0;
+        self.buf = Some(buf);
+        sync_dir_if_durable(&self.path, &self.options)?;
+
+        self.readers.insert(
+            self.segment,
+            segment_reader(&self.path, &self.options, self.segment)?,
+        );
+
+        Ok(())
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        // writes short of the next sync are synced now, errors have nowhere to go
+        if let Durability::FsyncEvery(_) = self.options.durability {
+            if self.unsynced > 0 {
+                let _ = self.sync_active();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keys and values are deliberately left out, they may be sensitive
+        f.debug_struct("KvStore")
+            .field("path", &self.path)
+            .field("segment", &self.segment)
+            .field("keys", &self.index.len())
+            .field("uncompacted", &self.uncompacted)
+            .field("readers", &self.readers.len())
+            .field("open_readers", &self.readers.open_len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kvs store at {} ({} keys, segment {}, {} bytes uncompacted)",
+            self.path.display(),
+            self.index.len(),
+            self.segment,
+            self.uncompacted
+        )
+    }
+}
+
+/// Returns 1 if two byte strings are equal and 0 otherwise, comparing every byte they share.
+fn ct_eq(a: &[u8], b: &[u8]) -> u8 {
+    let diff = a
+        .iter()
+        .zip(b)
+        .fold(u8::from(a.len() != b.len()), |diff, (x, y)| diff | (x ^ y));
+
+    u8::from(std::hint::black_box(diff) == 0)
+}
+
+/// Separates the namespace from the key in namespaced keys.
+///
+/// Namespace names must not contain it. Keys of other namespaces may, but keys of the
+/// empty namespace may not, as they are stored without a separator in front.
+const NAMESPACE_SEPARATOR: char = '\0';
+
+/// Returns the key under which a key of a namespace is stored.
+///
+/// Keys of the empty namespace are stored as-is.
+fn namespaced_key(ns: &str, key: String) -> Result<String> {
+    check_namespace(ns)?;
+
+    if ns.is_empty() {
+        if key.contains(NAMESPACE_SEPARATOR) {
+            let msg = format!("\"\": key {key:?} contains the namespace separator");
+            return Err(KvsError::InvalidNamespace(msg));
+        }
+        return Ok(key);
+    }
+
+    Ok(format!("{ns}{NAMESPACE_SEPARATOR}{key}"))
+}
+
+/// Fails if a namespace contains the namespace separator.
+fn check_namespace(ns: &str) -> Result<()> {
+    if ns.contains(NAMESPACE_SEPARATOR) {
+        let msg = format!("{ns:?}: contains the namespace separator");
+        return Err(KvsError::InvalidNamespace(msg));
+    }
+
+    Ok(())
+}
+
+/// Splits a stored key into its namespace and key.
+fn split_namespaced_key(key: &str) -> Option<(&str, &str)> {
+    match key.split_once(NAMESPACE_SEPARATOR) {
+        None => Some(("", key)),
+        Some(("", _)) => None,
+        Some(split) => Some(split),
+    }
+}
+
+/// Constructs a path to a segment file by combining the base path with a segment number
+/// Returns a `PathBuf` representing the full path to the segment file (e.g., "/base/path/123.log")
+fn segment_path(path: &Path, options: &KvStoreOptions, segment: u64) -> PathBuf {
+    path.join(options.segment_file_name(segment))
+}
+
+/// Creates the first free segment above both `segment` and every segment in the directory
+///
+/// Returns the number of the created segment and a buffered writer to it.
+fn create_next_segment(
+    path: &Path,
+    options: &KvStoreOptions,
+    segment: u64,
+) -> Result<(u64, BufWriter<File>)> {
+    let max = sorted_segments(path, options)?.last().copied().unwrap_or(0);
+    let mut next = max.max(segment) + 1;
+
+    loop {
+        match new_segment(path, options, next) {
+            // created by someone else since the directory was listed
+            Err(KvsError::Io(err)) if err.kind() == io::ErrorKind::AlreadyExists => next += 1,
+            res => return Ok((next, res?)),
+        }
+    }
+}
+
+/// Marks the start of a `physical_backup`, including a format version
+const BACKUP_MAGIC: &[u8; 8] = b"kvsbak01";
+
+/// Reads a little-endian `u64`, returning `None` at the end of the reader
+fn read_u64<R: Read>(r: &mut R) -> Result<Option<u64>> {
+    let mut bytes = [0; 8];
+
+    let mut read = 0;
+    while read < bytes.len() {
+        match r.read(&mut bytes[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(Some(u64::from_le_bytes(bytes)))
+}
+
+/// Fails on options that cannot be combined
+///
+/// Blobs are addressed by a hash of their plaintext, which would tell equal encrypted
+/// values apart from others and let guessed values be confirmed.
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+fn check_options(options: &KvStoreOptions) -> Result<()> {
+    #[cfg(feature = "encryption")]
+    if options.dedup_values && options.cipher.is_some() {
+        let msg = "dedup_values cannot be combined with a cipher";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+    }
+
+    Ok(())
+}
+
+/// Creates the store directory and its parents if required
+///
+/// Fails with `NotADirectory` if the path exists as something else, e.g. a segment file.
+fn create_store_dir(path: &Path) -> Result<()> {
+    if path.exists() && !path.is_dir() {
+        return Err(KvsError::NotADirectory(path.to_owned()));
+    }
+
+    Ok(fs::create_dir_all(path)?)
+}
+
+/// Allocates the `len` bytes of `file` after `offset`, leaving the length of the file alone
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (Ok(offset), Ok(len)) = (libc::off_t::try_from(offset), libc::off_t::try_from(len)) else {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    };
+    if len == 0 {
+        return Ok(());
+    }
+
+    // SAFETY: the descriptor is owned by `file`, which outlives the call
+    let res = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, offset, len) };
+    if res == 0 {
+        return Ok(());
+    }
+
+    // preallocation is only a hint, so filesystems without it are fine
+    match io::Error::last_os_error() {
+        err if err.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Allocates the `len` bytes of `file` after `offset`, which this platform cannot do
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Syncs the directory entries of the store, so newly created and removed segments survive a crash
+///
+/// Only done when new segments are synced, and only where directories can be opened as files.
+fn sync_dir_if_durable(path: &Path, options: &KvStoreOptions) -> Result<()> {
+    if options.syncs_segments() && cfg!(unix) {
+        File::open(path)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Creates a new segment file and returns a buffered writer to it
+///
+/// Fails if the segment file already exists, so existing segments are never appended to.
+fn new_segment(path: &Path, options: &KvStoreOptions, segment: u64) -> Result<BufWriter<File>> {
+    Ok(BufWriter::with_capacity(
+        500 * 1024, // 500 kB
+        OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(segment_path(path, options, segment))?,
+    ))
+}
+
+/// Creates the scratch file of a compaction, replacing one left over by a crashed compaction
+fn scratch_segment(path: &Path) -> Result<BufWriter<File>> {
+    Ok(BufWriter::with_capacity(
+        500 * 1024, // 500 kB
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?,
+    ))
+}
+
+/// Copies the command at `position` to the end of a compaction segment and returns its new position
+///
+/// The command is copied through `buf`, a chunk at a time.
+fn copy_command(
+    readers: &mut Readers,
+    position: CommandPosition,
+    dst: &mut BufWriter<File>,
+    segment: u64,
+    offset: &mut u64,
+    buf: &mut [u8],
+) -> Result<CommandPosition> {
+    let reader = readers.get_or_open(position.segment)?;
+    seek_reader(reader, position.offset)?;
+
+    let mut remaining = position.len;
+    while remaining > 0 {
+        let chunk = remaining.min(buf.len() as u64) as usize;
+        reader.read_exact(&mut buf[..chunk])?;
+        dst.write_all(&buf[..chunk])?;
+        remaining -= chunk as u64;
+    }
+
+    let new_position = CommandPosition {
+        segment,
+        offset: *offset,
+        len: position.len,
+    };
+    *offset += position.len; // update new offset
+
+    Ok(new_position)
+}
+
+/// Sorts positions by where they are in the log, so copying them reads each segment front to back
+fn in_log_order(positions: impl Iterator<Item = CommandPosition>) -> Vec<CommandPosition> {
+    let mut positions: Vec<CommandPosition> = positions.collect();
+    positions.sort_unstable_by_key(|position| (position.segment, position.offset));
+    positions
+}
+
+/// Moves a segment reader to `offset`, keeping what it buffered if `offset` is within it
+///
+/// Seeking a `BufReader` to an absolute offset drops its buffer, so reading commands
+/// that lie close together would refill the whole buffer for each of them.
+fn seek_reader(reader: &mut BufReader<File>, offset: u64) -> io::Result<()> {
+    let current = reader.stream_position()?;
+    reader.seek_relative(offset as i64 - current as i64)
+}
+
+/// Reads a command from a specific offset in a segment file
+///
+/// The index points at the command, so a missing segment fails with `MissingSegment` and
+/// a segment ending before the offset with `Corruption`, rather than reading as absent.
+fn read_command(readers: &mut Readers, segment: u64, offset: u64) -> Result<Command> {
+    let reader = readers.get_or_open(segment)?;
+    seek_reader(reader, offset)?;
+
+    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
+
+    match stream.next() {
+        Some(res) => Ok(res?),
+        None => Err(KvsError::Corruption(segment, offset)),
+    }
+}
+
+/// Reads the value of the `Set` or `Blob` command at `position`.
+pub(crate) fn read_value(
+    readers: &mut Readers,
+    options: &KvStoreOptions,
+    position: CommandPosition,
+) -> Result<Option<String>> {
+    match read_command(readers, position.segment, position.offset)? {
+        Command::Set { value, .. } | Command::Blob { value, .. } => {
+            Ok(Some(open_value(options, &value)?.into_owned()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Decrypts a value read from the log, if the store has a cipher.
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+fn open_value<'a>(options: &KvStoreOptions, value: &'a str) -> Result<Cow<'a, str>> {
+    #[cfg(feature = "encryption")]
+    if let Some(cipher) = &options.cipher {
+        return Ok(Cow::Owned(cipher.open(value)?));
+    }
+
+    Ok(Cow::Borrowed(value))
+}
+
+// Creates a buffered reader for the segment
+pub(crate) fn segment_reader(
+    path: &Path,
+    options: &KvStoreOptions,
+    segment: u64,
+) -> Result<BufReader<File>> {
+    Ok(BufReader::with_capacity(
+        options.read_buffer_size,
+        File::open(segment_path(path, options, segment))?,
+    ))
+}
+
+/// Loads a segment file into the index map, starting at offset `start`
+fn load_segment(
+    path: &Path,
+    options: &KvStoreOptions,
+    segment: u64,
+    start: u64,
+    loaded: &mut LoadedStore,
+) -> Result<SegmentLoad> {
+    let mut reader = segment_reader(path, options, segment)?;
+    reader.seek(SeekFrom::Start(start))?;
+    let mut stream = CommandReader::at(&mut reader, start);
+
+    let mut load = SegmentLoad::default();
+
+    // start of the undecodable bytes skipped so far with `CorruptionPolicy::SkipRecord`
+    let mut corrupt = None;
+
+    while let Some(res) = stream.next() {
+        let (offset, len, cmd) = match res {
+            Err(KvsError::Serde(_)) if options.on_corruption == CorruptionPolicy::SkipRecord => {
+                corrupt.get_or_insert(stream.offset);
+                if stream.resync()? {
+                    continue;
+                }
+                break;
+            }
+
+            // a torn write at the tail of the segment, e.g. after a crash mid-write
+            Err(KvsError::Serde(err))
+                if err.is_eof() || options.on_corruption == CorruptionPolicy::SealAndStartNew =>
+            {
+                load.skipped += 1;
+                load.torn = stream.reader.get_ref().metadata()?.len() - stream.offset;
+                break;
+            }
+
+            res => res?,
+        };
+
+        if let Some(start) = corrupt.take() {
+            load.skipped += 1;
+            let skipped = CommandPosition {
+                segment,
+                offset: start,
+                len: offset - start,
+            };
+            load.uncompacted += add_garbage(&mut loaded.garbage, Some(skipped));
+        }
+
+        load.records += 1;
+
+        let position = CommandPosition {
+            segment,
+            offset,
+            len,
+        };
+        load.uncompacted += index_command(
+            &mut loaded.index,
+            &mut loaded.blobs,
+            &mut loaded.modified,
+            &mut loaded.tombstones,
+            &mut loaded.versions,
+            &mut loaded.garbage,
+            cmd,
+            position,
+        );
+    }
+
+    // nothing decodes after the corruption, so it is treated like a torn write
+    if let Some(start) = corrupt {
+        load.skipped += 1;
+        load.torn = stream.reader.get_ref().metadata()?.len() - start;
+        stream.offset = start;
+    }
+
+    loaded.replayed.insert(segment, stream.offset);
+    loaded.readers.insert(segment, reader);
+
+    Ok(load)
+}
+
+/// Reads the commands of a segment one after another
+///
+/// Yields each command with its offset and length. Whitespace between commands, as left
+/// by hand-edited logs, is skipped and not counted towards either command.
+struct CommandReader<'a, R> {
+    reader: &'a mut R,
+    offset: u64,
+}
+
+impl<'a, R: BufRead> CommandReader<'a, R> {
+    /// Reads commands from the start of `reader`
+    fn new(reader: &'a mut R) -> CommandReader<'a, R> {
+        CommandReader::at(reader, 0)
+    }
+
+    /// Reads commands from `reader`, which is positioned at `offset` in its segment
+    fn at(reader: &'a mut R, offset: u64) -> CommandReader<'a, R> {
+        CommandReader { reader, offset }
+    }
+
+    /// Consumes whitespace, returns `false` at the end of the reader
+    fn skip_whitespace(&mut self) -> io::Result<bool> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(false);
+            }
+
+            let whitespace = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            let more = whitespace < buf.len();
+
+            self.reader.consume(whitespace);
+            self.offset += whitespace as u64;
+
+            if more {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CommandReader<'_, R> {
+    type Item = Result<(u64, u64, Command)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.skip_whitespace() {
+            Ok(false) => return None,
+            Err(err) => return Some(Err(err.into())),
+            Ok(true) => {}
+        }
+
+        // the deserializer reads no further than the end of the command
+        let mut counted = CountingReader {
+            reader: &mut *self.reader,
+            count: 0,
+        };
+        let res = Command::deserialize(&mut serde_json::Deserializer::from_reader(&mut counted));
+        let len = counted.count;
+
+        let offset = self.offset;
+        match res {
+            Ok(cmd) => {
+                self.offset += len;
+                Some(Ok((offset, len, cmd)))
+            }
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+}
+
+impl<R: BufRead + Seek> CommandReader<'_, R> {
+    /// Moves past the start of an undecodable command to the next `{` that may start one
+    ///
+    /// Returns `false` if there is none before the end of the reader.
+    fn resync(&mut self) -> Result<bool> {
+        self.offset += 1;
+        self.reader.seek(SeekFrom::Start(self.offset))?;
+
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(false);
+            }
+
+            let brace = buf.iter().position(|&b| b == b'{');
+            let skipped = brace.unwrap_or(buf.len());
+
+            self.reader.consume(skipped);
+            self.offset += skipped as u64;
+
+            if brace.is_some() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Writes a `Set` command whose value is read from `r`, encoded as serde would encode it
+fn write_streamed_set<W: Write, R: Read>(
+    w: &mut W,
+    key: &str,
+    r: &mut R,
+    modified: u64,
+    version: u64,
+) -> Result<()> {
+    w.write_all(br#"{"Set":{"key":"#)?;
+    serde_json::to_writer(&mut *w, key)?;
+    w.write_all(br#","value":""#)?;
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "value is not valid UTF-8");
+
+    let mut chunk = vec![0; 64 * 1024];
+    // bytes of a character split across reads, carried over to the next one
+    let mut pending = 0;
+
+    loop {
+        let n = match r.read(&mut chunk[pending..]) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            res => res?,
+        };
+        if n == 0 {
+            if pending > 0 {
+                return Err(invalid().into());
+            }
+            break;
+        }
+
+        let filled = pending + n;
+        let text = match std::str::from_utf8(&chunk[..filled]) {
+            Ok(text) => text,
+            Err(err) if err.error_len().is_none() => {
+                std::str::from_utf8(&chunk[..err.valid_up_to()]).expect("valid prefix")
+            }
+            Err(_) => return Err(invalid().into()),
+        };
+
+        // escape through serde, leaving out the quotes around the string
+        let escaped = serde_json::to_string(text)?;
+        w.write_all(&escaped.as_bytes()[1..escaped.len() - 1])?;
+
+        let valid = text.len();
+        chunk.copy_within(valid..filled, 0);
+        pending = filled - valid;
+    }
+
+    write!(w, r#"","modified":{modified},"version":{version}}}}}"#)?;
+
+    Ok(())
+}
+
+/// Counts the bytes written through it
+struct CountingWriter<W> {
+    writer: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Counts the bytes read through it
+struct CountingReader<R> {
+    reader: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Applies a command written at `position` to the index
+///
+/// Credits commands that became stale to the garbage of their segment and returns their bytes.
+// every piece of in-memory state a command can touch is passed on its own, so callers
+// can hand in fields of both `KvStore` and `LoadedStore`
+#[allow(clippy::too_many_arguments)]
+fn index_command(
+    index: &mut Index,
+    blobs: &mut Blobs,
+    modified: &mut HashMap<String, SystemTime>,
+    tombstones: &mut HashMap<String, CommandPosition>,
+    versions: &mut HashMap<String, u64>,
+    garbage: &mut HashMap<u64, u64>,
+    cmd: Command,
+    position: CommandPosition,
+) -> u64 {
+    let mut uncompacted = 0;
+
+    let key = match &cmd {
+        Command::Blob { hash, .. } => return add_garbage(garbage, blobs.insert(*hash, position)),
+        Command::Set { key, .. } | Command::SetRef { key, .. } | Command::Remove { key, .. } => key,
+    };
+    versions.insert(key.clone(), cmd.version());
+
+    // a removed key that is written again, or removed again, no longer needs its old tombstone
+    if let Some(tombstone) = tombstones.remove(key) {
+        uncompacted += add_garbage(garbage, Some(tombstone));
+    }
+
+    let old = match cmd {
+        Command::Remove { key, .. } => {
+            uncompacted += add_garbage(garbage, blobs.unassign(&key));
+            modified.remove(&key);
+            let old = index.remove(&key);
+            tombstones.insert(key, position);
+            old
+        }
+
+        Command::Set {
+            key,
+            modified: millis,
+            ..
+        } => {
+            uncompacted += add_garbage(garbage, blobs.unassign(&key));
+            modified.insert(key.clone(), from_millis(millis));
+            index.insert(key, position)
+        }
+
+        Command::SetRef {
+            key,
+            hash,
+            modified: millis,
+            ..
+        } => {
+            uncompacted += add_garbage(garbage, blobs.assign(key.clone(), hash));
+            modified.insert(key.clone(), from_millis(millis));
+            index.insert(key, position)
+        }
+
+        Command::Blob { .. } => unreachable!("blobs are indexed above"),
+    };
+
+    // key either
+    // - already existed, we can reclaim space of the old command
+    // - was removed, space can be reclaimed
+    uncompacted + add_garbage(garbage, old)
+}
+
+/// Credits a stale command to the garbage of its segment and returns its bytes
+fn add_garbage(garbage: &mut HashMap<u64, u64>, position: Option<CommandPosition>) -> u64 {
+    match position {
+        Some(position) => {
+            *garbage.entry(position.segment).or_insert(0) += position.len;
+            position.len
+        }
+        None => 0,
+    }
+}
+
+/// Loads all segments in the directory
+fn load_store(path: &Path, options: &KvStoreOptions) -> Result<LoadedStore> {
+    let segments = sorted_segments(path, options)?;
+
+    let mut loaded = LoadedStore {
+        index: hash::index_with_capacity(options.expected_keys),
+        readers: Readers::new(path, options),
+        blobs: Blobs::default(),
+        modified: HashMap::with_capacity(options.expected_keys),
+        tombstones: HashMap::new(),
+        versions: HashMap::new(),
+        garbage: HashMap::new(),
+        replayed: HashMap::new(),
+        report: RecoveryReport {
+            unexpected_files: unexpected_files(path, options)?,
+            ..RecoveryReport::default()
+        },
+        segments,
+    };
+
+    for segment in loaded.segments.clone() {
+        let load = load_segment(path, options, segment, 0, &mut loaded)?;
+
+        // a torn tail is garbage, unless a reader replays it once the write completed
+        *loaded.garbage.entry(segment).or_insert(0) += load.torn;
+
+        loaded.report.segments_loaded += 1;
+        loaded.report.records_replayed += load.records;
+        loaded.report.records_skipped += load.skipped;
+        loaded.report.uncompacted_bytes += load.uncompacted + load.torn;
+    }
+
+    // blobs whose keys were all overwritten or removed
+    for blob in loaded.blobs.retain_referenced() {
+        loaded.report.uncompacted_bytes += add_garbage(&mut loaded.garbage, Some(blob));
+    }
+
+    Ok(loaded)
+}
+
+/// Replays the segments of the store at `path` into an index, without opening the store.
+///
+/// Each segment file is closed as soon as it is replayed, so at most one is open at a
+/// time, and nothing is kept open afterwards. This suits tooling that checks whether many
+/// store directories load and how many keys they hold. Segments are expected to be named
+/// as with the default options.
+pub fn scan_index_only(path: impl AsRef<Path>) -> Result<HashMap<String, CommandPositionPublic>> {
+    let path = path.as_ref();
+    let options = KvStoreOptions::default();
+
+    let mut loaded = LoadedStore {
+        index: Index::default(),
+        readers: Readers::new(path, &options),
+        blobs: Blobs::default(),
+        modified: HashMap::new(),
+        tombstones: HashMap::new(),
+        versions: HashMap::new(),
+        garbage: HashMap::new(),
+        replayed: HashMap::new(),
+        report: RecoveryReport::default(),
+        segments: sorted_segments(path, &options)?,
+    };
+
+    for segment in loaded.segments.clone() {
+        load_segment(path, &options, segment, 0, &mut loaded)?;
+        loaded.readers.remove(segment);
+    }
+
+    Ok(loaded
+        .index
+        .into_iter()
+        .map(|(key, position)| (key, position.into()))
+        .collect())
+}
+
+/// Checks that the store at `path` looks usable, without loading it.
+///
+/// This is a cheap probe for orchestration: it checks that the directory exists, that
+/// every file named like a segment has a valid segment number, and that the newest
+/// segment can be opened for writing, without reading any commands. Segments are
+/// expected to be named as with the default options. Problems are reported in the
+/// returned `Health` rather than as errors.
+pub fn healthcheck(path: impl AsRef<Path>) -> Result<Health> {
+    let path = path.as_ref();
+    let options = KvStoreOptions::default();
+    let mut health = Health::default();
+
+    if !path.is_dir() {
+        health
+            .details
+            .push(format!("{} is not a directory", path.display()));
+        return Ok(health);
+    }
+
+    let entries = match dir_entries(path) {
+        Ok(entries) => entries,
+        Err(err) => {
+            health.details.push(format!("cannot list directory: {err}"));
+            return Ok(health);
+        }
+    };
+
+    let suffix = format!(".{}", options.extension);
+    let mut newest = None;
+
+    for entry in &entries {
+        match segment_number(entry, &options) {
+            Some(segment) => newest = newest.max(Some(segment)),
+            None if entry.to_str().is_some_and(|name| name.ends_with(&suffix)) => {
+                health
+                    .details
+                    .push(format!("{} is not a valid segment name", entry.display()));
+            }
+            None => {}
+        }
+    }
+
+    if let Some(segment) = newest {
+        let segment_path = segment_path(path, &options, segment);
+        if let Err(err) = OpenOptions::new().append(true).open(&segment_path) {
+            health
+                .details
+                .push(format!("{} is not writable: {err}", segment_path.display()));
+        }
+    }
+
+    health.ok = health.details.is_empty();
+
+    Ok(health)
+}
+
+/// Copies the live keys of the store at `old_path` into a new store at `new_path`.
+///
+/// The old store is read like any store opened with the default options, which includes
+/// logs of bare JSON commands as written by every version so far. The new store is
+/// written with `options`, keeping the write time of each key. Values are written as
+/// plain values even with `dedup_values` enabled, which only applies to later writes.
+/// Fails with `AlreadyExists` if there already is a store at `new_path`.
+pub fn migrate(
+    old_path: impl Into<PathBuf>,
+    new_path: impl Into<PathBuf>,
+    options: KvStoreOptions,
+) -> Result<MigrationReport> {
+    let new_path: PathBuf = new_path.into();
+    if new_path.is_dir() && !sorted_segments(&new_path, &options)?.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "store exists").into());
+    }
+
+    let mut old = KvStore::open_read_only(old_path, KvStoreOptions::default())?;
+    let mut new = KvStore::open_with_options(new_path, options)?;
+
+    let mut keys: Vec<String> = old.index.keys().cloned().collect();
+    keys.sort_unstable();
+
+    let mut report = MigrationReport::default();
+
+    for chunk in keys.chunks(MIGRATION_CHUNK) {
+        let mut cmds = Vec::with_capacity(chunk.len());
+
+        for key in chunk {
+            let value = match old.get(key)? {
+                None => continue,
+                Some(value) => value,
+            };
+            let modified = old.modified.get(key).map_or(0, |&time| to_millis(time));
+
+            cmds.push(Command::Set {
+                key: key.clone(),
+                value: new.seal_value(value),
+                modified,
+                version: old.versions.get(key).copied().unwrap_or(0),
+            });
+        }
+
+        report.records_migrated += cmds.len() as u64;
+        new.write_commands(cmds)?;
+    }
+
+    new.flush()?;
+
+    Ok(report)
+}
+
+/// Number of keys `migrate` writes with a single flush
+const MIGRATION_CHUNK: usize = 1024;
+
+/// Names of files the store may keep in its directory next to the segments
+const METADATA_FILES: &[&str] = &[
+    ".lock",
+    "MANIFEST",
+    "index.snapshot",
+    COMPACTION_SCRATCH,
+    RENAME_PROBE,
+    RENAME_PROBE_TMP,
+];
+
+/// Name of the file `compact` writes the compacted segment to before renaming it into place
+const COMPACTION_SCRATCH: &str = "compaction.tmp";
+
+/// Names of the scratch files of `probe_atomic_rename`
+const RENAME_PROBE: &str = ".rename-probe";
+const RENAME_PROBE_TMP: &str = ".rename-probe.tmp";
+
+/// Checks that renaming a file over an existing one in the directory replaces it
+///
+/// Any failure counts as renames not working, rather than failing to open the store.
+fn probe_atomic_rename(path: &Path) -> bool {
+    let target = path.join(RENAME_PROBE);
+    let tmp = path.join(RENAME_PROBE_TMP);
+
+    let probe = || -> io::Result<bool> {
+        fs::write(&target, b"old")?;
+        fs::write(&tmp, b"new")?;
+        fs::rename(&tmp, &target)?;
+
+        Ok(fs::read(&target)? == b"new" && !tmp.exists())
+    };
+    let res = probe();
+
+    let _ = fs::remove_file(&tmp);
+    let _ = fs::remove_file(&target);
+
+    res.unwrap_or(false)
+}
+
+/// Returns the segment number of a directory entry, or `None` if it is not a segment
+///
+/// This is the single definition of what counts as a segment: a file, not named like
+/// a metadata file, whose name parses as a segment file name.
+fn segment_number(entry: &Path, options: &KvStoreOptions) -> Option<u64> {
+    let name = entry.file_name()?.to_str()?;
+    if METADATA_FILES.contains(&name) || !entry.is_file() {
+        return None;
+    }
+
+    options.parse_segment_file_name(name)
+}
+
+/// Returns the paths of all entries in the directory
+fn dir_entries(path: &Path) -> Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(path)?
+        .flat_map(|f| -> Result<_> { Ok(f?.path()) })
+        .collect())
+}
+
+/// Returns the files in the directory that are neither segments nor metadata files
+fn unexpected_files(path: &Path, options: &KvStoreOptions) -> Result<Vec<PathBuf>> {
+    let mut entries: Vec<PathBuf> = dir_entries(path)?
+        .into_iter()
+        .filter(|f| segment_number(f, options).is_none())
+        .filter(|f| {
+            f.file_name()
+                .and_then(OsStr::to_str)
+                .is_none_or(|name| !METADATA_FILES.contains(&name))
+        })
+        .collect();
+
+    entries.sort();
+
+    Ok(entries)
+}
+
+/// Returns a sorted list of all segment numbers in the directory
+fn sorted_segments(path: &Path, options: &KvStoreOptions) -> Result<Vec<u64>> {
+    let mut entries: Vec<u64> = dir_entries(path)?
+        .iter()
+        .flat_map(|f| segment_number(f, options))
+        .collect();
+
+    entries.sort();
+
+    Ok(entries)
+}
+
+/// Represents the commands that can be stored in the log files
+///
+/// Each command is serialized to JSON and written to the log files.
+/// - Set: Stores a key-value pair
+/// - Remove: Removes a key and its associated value
+/// - Blob: Stores a value shared by every key with an identical value
+/// - SetRef: Stores a key whose value is the blob with the given hash
+///
+/// `modified` is the write time in milliseconds since the Unix epoch; it is missing,
+/// and read as the epoch, in commands written before it existed. `version` counts the
+/// writes of the key, see `KvStore::get_versioned`; it is missing, and read as 0, in
+/// commands written before it existed.
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        modified: u64,
+        #[serde(default)]
+        version: u64,
+    },
+    Remove {
+        key: String,
+        #[serde(default)]
+        version: u64,
+    },
+    Blob {
+        hash: u64,
+        value: String,
+    },
+    SetRef {
+        key: String,
+        hash: u64,
+        #[serde(default)]
+        modified: u64,
+        #[serde(default)]
+        version: u64,
+    },
+}
+
+impl Command {
+    /// Returns the key the command writes, `None` for blobs.
+    fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetRef { key, .. }
+            | Command::Remove { key, .. } => Some(key),
+            Command::Blob { .. } => None,
+        }
+    }
+
+    /// Returns the version of the key the command writes, 0 for blobs.
+    fn version(&self) -> u64 {
+        match self {
+            Command::Set { version, .. }
+            | Command::SetRef { version, .. }
+            | Command::Remove { version, .. } => *version,
+            Command::Blob { .. } => 0,
+        }
+    }
+}
+
+/// A write of a key found by `KvStore::history`, before blob references are resolved
+enum KeyWrite {
+    Value(String),
+    Blob(u64),
+    Remove,
+}
+
+/// The value of a `Set` or `Blob` command, borrowed from the buffer it is decoded from
+#[derive(Deserialize)]
+enum ValueCommand<'a> {
+    Set {
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+    },
+    Blob {
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+    },
+}
+
+/// Returns the current time in milliseconds since the Unix epoch
+fn now_millis() -> u64 {
+    to_millis(SystemTime::now())
+}
+
+/// Converts a `SystemTime` to milliseconds since the Unix epoch
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Converts milliseconds since the Unix epoch to a `SystemTime`
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// Whether a key holds a value, see `KvStore::get_status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetStatus {
+    /// The key holds the value.
+    Present(String),
+
+    /// The key was removed.
+    Removed,
+
+    /// The key was never written.
+    NeverWritten,
+}
+
+/// State recovered from disk while opening a store, see `KvStore::open_with_report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Number of segments loaded.
+    pub segments_loaded: usize,
+
+    /// Number of commands replayed into the index.
+    pub records_replayed: u64,
+
+    /// Bytes held by stale commands found while replaying.
+    pub uncompacted_bytes: u64,
+
+    /// Number of commands skipped because they could not be decoded, e.g. torn writes.
+    pub records_skipped: u64,
+
+    /// Files in the store directory that are not segments.
+    pub unexpected_files: Vec<PathBuf>,
+}
+
+/// State of a store loaded from its segments.
+struct LoadedStore {
+    segments: Vec<u64>,
+    index: Index,
+    readers: Readers,
+    blobs: Blobs,
+    modified: HashMap<String, SystemTime>,
+    tombstones: HashMap<String, CommandPosition>,
+    versions: HashMap<String, u64>,
+    garbage: HashMap<u64, u64>,
+    replayed: HashMap<u64, u64>,
+    report: RecoveryReport,
+}
+
+/// Totals of replaying a single segment.
+#[derive(Default)]
+struct SegmentLoad {
+    records: u64,
+    skipped: u64,
+    uncompacted: u64,
+    // bytes of a torn command at the end
+    torn: u64,
+}
+
+/// Outcome of checking the index against the segments, see `KvStore::verify`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of keys checked.
+    pub checked: usize,
+
+    /// Keys whose position does not hold a readable command.
+    pub missing: Vec<String>,
+
+    /// Keys whose position holds a command that does not write them.
+    pub mismatched: Vec<String>,
+
+    /// Keys whose command on disk is not as long as their position says.
+    pub wrong_length: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Returns whether the index and the segments agree.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.wrong_length.is_empty()
+    }
+}
+
+/// Segments written by `KvStore::physical_backup` or read by `KvStore::restore_physical`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupManifest {
+    /// Number and length in bytes of each segment, in order.
+    pub segments: Vec<(u64, u64)>,
+}
+
+/// Outcome of probing a store directory, see `healthcheck`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    /// Whether no problems were found.
+    pub ok: bool,
+
+    /// A description of each problem found.
+    pub details: Vec<String>,
+}
+
+/// Outcome of copying a store into a new one, see `migrate`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Number of keys written to the new store.
+    pub records_migrated: u64,
+}
+
+/// Outcome of a compaction, see `KvStore::compact`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionResult {
+    /// Bytes of the segments that were rewritten.
+    pub bytes_before: u64,
+
+    /// Bytes of the compacted segment that replaced them.
+    pub bytes_after: u64,
+
+    /// Number of live commands copied to the compacted segment.
+    pub records_rewritten: u64,
+
+    /// Bytes freed on disk.
+    pub reclaimed: u64,
+}
+
+/// Estimated outcome of a compaction, see `KvStore::estimate_compaction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// Bytes held by stale commands that compaction would reclaim.
+    pub reclaimable_bytes: u64,
+
+    /// Bytes of live commands that compaction would rewrite.
+    pub live_bytes: u64,
+
+    /// Number of segments compaction would read from and remove.
+    pub segments_involved: usize,
+}
+
+/// Public representation of a command stored in a segment, see `KvStore::dump_segment`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicCommand {
+    /// Stores a key-value pair.
+    Set {
+        /// The key.
+        key: String,
+        /// The value.
+        value: String,
+    },
+
+    /// Removes a key and its associated value.
+    Remove {
+        /// The key.
+        key: String,
+    },
+
+    /// Stores a value shared by every key with an identical value.
+    Blob {
+        /// The hash of the value.
+        hash: u64,
+        /// The value.
+        value: String,
+    },
+
+    /// Stores a key whose value is the blob with the given hash.
+    SetRef {
+        /// The key.
+        key: String,
+        /// The hash of the value.
+        hash: u64,
+    },
+}
+
+impl From<Command> for PublicCommand {
+    fn from(cmd: Command) -> PublicCommand {
+        match cmd {
+            Command::Set { key, value, .. } => PublicCommand::Set { key, value },
+            Command::Remove { key, .. } => PublicCommand::Remove { key },
+            Command::Blob { hash, value } => PublicCommand::Blob { hash, value },
+            Command::SetRef { key, hash, .. } => PublicCommand::SetRef { key, hash },
+        }
+    }
+}
+
+/// Represents the command position in a segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CommandPosition {
+    pub(crate) segment: u64,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+/// Public, serializable view of where the command of a key is stored, see `KvStore::index_snapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandPositionPublic {
+    /// The segment holding the command.
+    pub segment: u64,
+
+    /// The offset of the command in the segment.
+    pub offset: u64,
+
+    /// The length of the command in bytes.
+    pub len: u64,
+}
+
+impl From<CommandPosition> for CommandPositionPublic {
+    fn from(position: CommandPosition) -> CommandPositionPublic {
+        CommandPositionPublic {
+            segment: position.segment,
+            offset: position.offset,
+            len: position.len,
+        }
+    }
+}
@@ -1,8 +1,39 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
+pub use batch::WriteBatch;
+#[cfg(feature = "encryption")]
+pub use cipher::ValueCipher;
 pub use error::{KvsError, Result};
-pub use kv::KvStore;
+#[cfg(feature = "fault-injection")]
+pub use fault::{FaultInjector, FaultPoint};
+pub use kv::{
+    healthcheck, migrate, scan_index_only, BackupManifest, CommandPositionPublic,
+    CompactionEstimate, CompactionResult, GetStatus, Health, KvStore, MigrationReport,
+    PublicCommand, RecoveryReport, VerifyReport,
+};
+pub use map::MapAdapter;
+pub use options::{CompactionPolicy, CorruptionPolicy, Durability, KvStoreOptions};
+pub use pipeline::{OpResult, Pipeline};
+pub use snapshot::Snapshot;
+pub use stats::OpCounters;
 
+mod batch;
+#[cfg(feature = "encryption")]
+mod cipher;
+mod commit;
+mod dedup;
+mod error;
+#[cfg(feature = "fault-injection")]
+mod fault;
+mod hash;
 mod kv;
-mod error;
\ No newline at end of file
+mod lru;
+mod map;
+mod options;
+mod pattern;
+mod pipeline;
+mod readers;
+mod snapshot;
+mod stats;
+mod value_reader;
@@ -0,0 +1,63 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Tracks the keys of a store in the order they were last accessed, with their bytes.
+///
+/// Used to evict the least recently used keys once the store holds more live bytes
+/// than `KvStoreOptions::max_live_bytes` allows.
+#[derive(Default)]
+pub(crate) struct Lru {
+    // when each key was last accessed and the bytes of its command
+    keys: HashMap<String, (u64, u64)>,
+    order: BTreeMap<u64, String>,
+    bytes: u64,
+    // incremented on every access
+    clock: u64,
+}
+
+impl Lru {
+    /// Records `key` as accessed just now, holding `bytes` bytes.
+    pub(crate) fn insert(&mut self, key: &str, bytes: u64) {
+        self.remove(key);
+
+        self.clock += 1;
+        self.keys.insert(key.to_owned(), (self.clock, bytes));
+        self.order.insert(self.clock, key.to_owned());
+        self.bytes += bytes;
+    }
+
+    /// Records `key` as accessed just now, if it is tracked.
+    pub(crate) fn touch(&mut self, key: &str) {
+        if let Some(&(_, bytes)) = self.keys.get(key) {
+            self.insert(key, bytes);
+        }
+    }
+
+    /// Stops tracking `key`.
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some((tick, bytes)) = self.keys.remove(key) {
+            self.order.remove(&tick);
+            self.bytes -= bytes;
+        }
+    }
+
+    /// Returns the least recently used keys that have to go for the rest to fit in
+    /// `budget` bytes, oldest first, without forgetting them.
+    ///
+    /// The most recently used key is never returned.
+    pub(crate) fn over_budget(&self, budget: u64) -> Vec<String> {
+        let mut bytes = self.bytes;
+
+        self.order
+            .values()
+            .take(self.keys.len().saturating_sub(1))
+            .take_while(|&key| {
+                if bytes <= budget {
+                    return false;
+                }
+                bytes -= self.keys.get(key).map_or(0, |&(_, bytes)| bytes);
+                true
+            })
+            .cloned()
+            .collect()
+    }
+}
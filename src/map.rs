@@ -0,0 +1,76 @@
+use crate::{KvStore, Result};
+
+/// A store behind the method names of `HashMap`, for code written against a map.
+///
+/// Every method forwards to the store, so writes are persisted and last-write-wins as with
+/// `KvStore` itself. Methods that touch the log return a `Result`, and reads take `&mut
+/// self` and return owned values, as values are read from disk. `insert` and `remove`
+/// return the previous value like their `HashMap` namesakes, which costs a read.
+#[derive(Debug)]
+pub struct MapAdapter {
+    store: KvStore,
+}
+
+impl MapAdapter {
+    /// Wraps a store.
+    pub fn new(store: KvStore) -> MapAdapter {
+        MapAdapter { store }
+    }
+
+    /// Returns the wrapped store.
+    pub fn into_inner(self) -> KvStore {
+        self.store
+    }
+
+    /// Sets the value of a key and returns its previous value, if any.
+    pub fn insert(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old = self.store.get(&key)?;
+        self.store.set(key, value)?;
+
+        Ok(old)
+    }
+
+    /// Returns the value of a key, if it exists.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    /// Removes a key and returns its value, if it existed.
+    pub fn remove(&mut self, key: &str) -> Result<Option<String>> {
+        let old = self.store.get(key)?;
+        if old.is_some() {
+            self.store.remove(key)?;
+        }
+
+        Ok(old)
+    }
+
+    /// Returns whether a key exists, without reading its value.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.store.contains_key(key)
+    }
+
+    /// Returns the number of keys.
+    pub fn len(&self) -> usize {
+        self.store.key_count()
+    }
+
+    /// Returns whether there are no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every key, in one batch.
+    pub fn clear(&mut self) -> Result<()> {
+        let keys: Vec<String> = self.store.index_snapshot().into_keys().collect();
+        self.store.remove_many(&keys)?;
+
+        Ok(())
+    }
+}
+
+impl From<KvStore> for MapAdapter {
+    fn from(store: KvStore) -> MapAdapter {
+        MapAdapter::new(store)
+    }
+}
@@ -0,0 +1,343 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "encryption")]
+use crate::cipher::{Cipher, ValueCipher};
+
+/// Options for opening a `KvStore`.
+///
+/// Options are built by chaining setters on `KvStoreOptions::new()`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(crate) prefix: String,
+    pub(crate) extension: String,
+    pub(crate) dedup_values: bool,
+    pub(crate) durability: Durability,
+    pub(crate) expected_keys: usize,
+    pub(crate) keep_recent_segments: usize,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) max_open_readers: Option<usize>,
+    pub(crate) cold_after_compactions: Option<usize>,
+    pub(crate) commit_interval: Option<Duration>,
+    pub(crate) auto_compact: bool,
+    pub(crate) on_corruption: CorruptionPolicy,
+    pub(crate) max_live_bytes: Option<u64>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) compaction_io_buffer: usize,
+    pub(crate) compaction_policy: CompactionPolicy,
+    pub(crate) key_normalizer: Option<KeyNormalizer>,
+    #[cfg(feature = "encryption")]
+    pub(crate) cipher: Option<Cipher>,
+}
+
+/// How far writes are persisted before an operation returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Writes are handed to the operating system, surviving a crash of the process
+    /// but not necessarily a crash of the machine.
+    #[default]
+    Flush,
+
+    /// Writes are synced to disk, as are the directory entries of new segment files.
+    Sync,
+
+    /// Writes are handed to the operating system, and synced to disk once every given
+    /// number of commands.
+    ///
+    /// A crash of the machine loses at most the last `n - 1` commands written before it;
+    /// a write batch counts one command per write. The count starts over on every sync,
+    /// including the ones of `KvStore::flush`, of sealing the active segment on
+    /// compaction, and of dropping the store. New and compacted segment files are synced
+    /// as with `Sync`. `FsyncEvery(0)` and `FsyncEvery(1)` sync every write.
+    FsyncEvery(usize),
+}
+
+/// How opening a store handles undecodable commands, see `KvStoreOptions::on_corruption`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CorruptionPolicy {
+    /// Opening fails.
+    #[default]
+    Fail,
+
+    /// Undecodable bytes are skipped up to the next command that decodes, keeping every
+    /// command before and after them.
+    SkipRecord,
+
+    /// Loading the segment stops at the first undecodable command, keeping every command
+    /// before it. The rest of the segment is ignored and writes go to a new segment.
+    SealAndStartNew,
+}
+
+/// Which segments compaction rewrites, see `KvStoreOptions::compaction_policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CompactionPolicy {
+    /// Every segment is rewritten into one.
+    #[default]
+    Full,
+
+    /// Segments are grouped into tiers by size, each up to 4 times larger than the one
+    /// below, starting at 64 kB. Compaction merges the 4 or more sealed segments of the
+    /// lowest full tier into one, which then counts towards the next tier, and otherwise
+    /// only seals the active segment so it joins the lowest tier. Like in LSM trees, data
+    /// is rewritten once per tier instead of on every compaction, at the cost of garbage
+    /// in other tiers lingering until their tier is merged.
+    SizeTiered,
+}
+
+impl KvStoreOptions {
+    /// Creates options with the default values.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Sets the segment file extension, `log` by default.
+    ///
+    /// A leading `.` is ignored, so `kvslog` and `.kvslog` are equivalent.
+    pub fn extension(mut self, extension: impl Into<String>) -> KvStoreOptions {
+        self.extension = extension.into().trim_start_matches('.').to_owned();
+        self
+    }
+
+    /// Sets a prefix for segment file names, empty by default.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> KvStoreOptions {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Stores identical values only once, disabled by default.
+    ///
+    /// Each distinct value is written as a blob referenced by every key holding it, and
+    /// compaction keeps a blob for as long as any key references it. Stores written with
+    /// this enabled can be opened with it disabled, and the other way around. Blobs are
+    /// addressed by a hash of their value, so opening fails if a `cipher` is set too.
+    pub fn dedup_values(mut self, dedup_values: bool) -> KvStoreOptions {
+        self.dedup_values = dedup_values;
+        self
+    }
+
+    /// Sets how far writes are persisted before an operation returns, `Flush` by default.
+    pub fn durability(mut self, durability: Durability) -> KvStoreOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// Pre-sizes the in-memory index for the given number of keys, 0 by default.
+    ///
+    /// This avoids repeatedly growing the index while opening large stores.
+    pub fn expected_keys(mut self, expected_keys: usize) -> KvStoreOptions {
+        self.expected_keys = expected_keys;
+        self
+    }
+
+    /// Leaves the given number of most recent segments out of compaction, 0 by default.
+    ///
+    /// Compaction seals the active segment, which then counts as the most recent one.
+    /// This saves rewriting recently written keys that are likely to change again, at the
+    /// cost of their garbage lingering until the segments age out of the window. That
+    /// garbage does not count towards the next compaction, so it is not triggered right away.
+    /// Ignored with `dedup_values` enabled, as blobs must precede the keys referencing them.
+    pub fn keep_recent_segments(mut self, keep_recent_segments: usize) -> KvStoreOptions {
+        self.keep_recent_segments = keep_recent_segments;
+        self
+    }
+
+    /// Sets the buffer size of segment readers in bytes, 8 KiB by default.
+    ///
+    /// Larger buffers save system calls when reading through whole segments, as opening
+    /// and compacting do, but are wasted on point lookups. Buffers hold at least one byte.
+    pub fn read_buffer_size(mut self, read_buffer_size: usize) -> KvStoreOptions {
+        self.read_buffer_size = read_buffer_size.max(1);
+        self
+    }
+
+    /// Sets which segments compaction rewrites, `Full` by default.
+    ///
+    /// Ignored with `dedup_values` enabled, as blobs must precede the keys referencing them.
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> KvStoreOptions {
+        self.compaction_policy = compaction_policy;
+        self
+    }
+
+    /// Sets the size of the buffer compaction copies commands through, 8 KiB by default.
+    ///
+    /// Together with the segment readers and writer this bounds the memory compaction
+    /// uses, see `KvStore::compact`. Larger commands are copied in chunks. Buffers hold at
+    /// least one byte.
+    pub fn compaction_io_buffer(mut self, compaction_io_buffer: usize) -> KvStoreOptions {
+        self.compaction_io_buffer = compaction_io_buffer.max(1);
+        self
+    }
+
+    /// Limits how many segment files are kept open for reading at once, unlimited by default.
+    ///
+    /// Over the limit, the least recently used segment is closed and reopened when it is
+    /// read again. The active segment always stays open, so at least one is.
+    pub fn max_open_readers(mut self, max_open_readers: usize) -> KvStoreOptions {
+        self.max_open_readers = Some(max_open_readers.max(1));
+        self
+    }
+
+    /// Leaves segments out of compaction once their data is cold, disabled by default.
+    ///
+    /// The segment written by a compaction is one generation past the oldest data it was
+    /// compacted from. Once a segment is the given number of generations old and none of
+    /// its commands went stale, compaction leaves it in place instead of rewriting it, until
+    /// a write to one of its keys makes it churn again. Generations are only tracked while
+    /// a store is open, so data has to age again after reopening. Segments are at least
+    /// one generation old to be cold.
+    pub fn cold_after_compactions(mut self, cold_after_compactions: usize) -> KvStoreOptions {
+        self.cold_after_compactions = Some(cold_after_compactions.max(1));
+        self
+    }
+
+    /// Compacts automatically on writes once `KvStore::should_compact` says so, enabled by default.
+    ///
+    /// With this disabled writes never compact, including batch writes, and compaction only
+    /// happens when `KvStore::compact` is called.
+    pub fn auto_compact(mut self, auto_compact: bool) -> KvStoreOptions {
+        self.auto_compact = auto_compact;
+        self
+    }
+
+    /// Sets how opening handles undecodable commands, `Fail` by default.
+    ///
+    /// A torn write at the very end of a segment is always skipped, while corruption
+    /// anywhere else fails opening under `Fail`. This is meant for a corrupted active
+    /// segment, but applies to every segment: once written past, the corrupted segment is
+    /// no longer the newest one and must still load. Skipped commands are counted in
+    /// `RecoveryReport::records_skipped`.
+    pub fn on_corruption(mut self, on_corruption: CorruptionPolicy) -> KvStoreOptions {
+        self.on_corruption = on_corruption;
+        self
+    }
+
+    /// Bounds the live bytes of the store like a cache, unbounded by default.
+    ///
+    /// Once writes take the store over the budget, the least recently used keys are
+    /// removed until it is within it again. Keys count as used when they are set or read
+    /// with `KvStore::get`, and with the bytes reported by `KvStore::value_len`. The most
+    /// recently used key is never removed, even if it alone is over the budget. Access
+    /// order is not persisted: after reopening, keys count as used when last written.
+    pub fn max_live_bytes(mut self, max_live_bytes: u64) -> KvStoreOptions {
+        self.max_live_bytes = Some(max_live_bytes);
+        self
+    }
+
+    /// Fails reads of values taking longer than the timeout with `Timeout`, disabled by default.
+    ///
+    /// Meant for storage that may stall, like a flaky network mount. File reads cannot be
+    /// cancelled, so each read of a value, as by `KvStore::get`, opens the segment and
+    /// reads it on a thread of its own, which is left behind if it does not finish in time.
+    /// This makes reads considerably slower. Loading and compacting the store are not bounded.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> KvStoreOptions {
+        self.read_timeout = Some(read_timeout);
+        self
+    }
+
+    /// Syncs writes to disk on a background thread at most once per interval, disabled by default.
+    ///
+    /// Writes are handed to the operating system right away, and the thread syncs all of
+    /// them at once when the interval is up. Writes then return only once they are synced,
+    /// except for `KvStore::set_relaxed`, which returns right away. This takes the place of
+    /// the per-write syncs of `Durability::Sync`, while new segments and compactions are
+    /// synced as with it. Dropping the store syncs what is left before stopping the thread.
+    pub fn commit_interval(mut self, commit_interval: Duration) -> KvStoreOptions {
+        self.commit_interval = Some(commit_interval);
+        self
+    }
+
+    /// Returns whether new and compacted segment files are synced to disk.
+    pub(crate) fn syncs_segments(&self) -> bool {
+        matches!(
+            self.durability,
+            Durability::Sync | Durability::FsyncEvery(_)
+        ) || self.commit_interval.is_some()
+    }
+
+    /// Encrypts values at rest with the given cipher, disabled by default.
+    ///
+    /// A store must always be opened with the cipher it was written with; values written
+    /// without one cannot be read with one, and the other way around.
+    /// Only values are encrypted: keys are always stored in plaintext.
+    #[cfg(feature = "encryption")]
+    pub fn cipher(mut self, cipher: impl ValueCipher + 'static) -> KvStoreOptions {
+        self.cipher = Some(Cipher(Arc::new(cipher)));
+        self
+    }
+
+    /// Normalizes every key passed to the store with `normalizer`, disabled by default.
+    ///
+    /// Keys are normalized before they touch the index, by every method taking a key,
+    /// and stored normalized, e.g. lowercased for case-insensitive keys. The normalizer
+    /// must be idempotent, as keys may be normalized more than once on their way in.
+    /// A store must always be opened with the normalizer it was written with; keys
+    /// written without one are left as they are and may no longer be found, until
+    /// `KvStore::transform_keys` moves them to their normalized key.
+    pub fn key_normalizer(
+        mut self,
+        normalizer: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> KvStoreOptions {
+        self.key_normalizer = Some(KeyNormalizer(Arc::new(normalizer)));
+        self
+    }
+
+    /// Returns `key` as stored, see `key_normalizer`.
+    pub(crate) fn normalize_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match &self.key_normalizer {
+            Some(normalizer) => Cow::Owned((normalizer.0)(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Returns the file name of a segment, e.g. `123.log`.
+    pub(crate) fn segment_file_name(&self, segment: u64) -> String {
+        format!("{}{segment}.{}", self.prefix, self.extension)
+    }
+
+    /// Parses the segment number out of a file name produced by `segment_file_name`.
+    pub(crate) fn parse_segment_file_name(&self, name: &str) -> Option<u64> {
+        name.strip_prefix(self.prefix.as_str())?
+            .strip_suffix(self.extension.as_str())?
+            .strip_suffix('.')?
+            .parse()
+            .ok()
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            prefix: String::new(),
+            extension: "log".to_owned(),
+            dedup_values: false,
+            durability: Durability::default(),
+            expected_keys: 0,
+            keep_recent_segments: 0,
+            read_buffer_size: 8 * 1024,
+            max_open_readers: None,
+            cold_after_compactions: None,
+            commit_interval: None,
+            auto_compact: true,
+            on_corruption: CorruptionPolicy::default(),
+            max_live_bytes: None,
+            read_timeout: None,
+            compaction_io_buffer: 8 * 1024,
+            compaction_policy: CompactionPolicy::default(),
+            key_normalizer: None,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        }
+    }
+}
+
+/// A shared key normalizer, so options stay cheap to clone.
+#[derive(Clone)]
+pub(crate) struct KeyNormalizer(Arc<dyn Fn(&str) -> String + Send + Sync>);
+
+impl fmt::Debug for KeyNormalizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyNormalizer")
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{KvsError, Result};
+
+/// A compiled glob pattern matching keys.
+///
+/// `?` matches any character, `*` any sequence of characters, `[abc]` and `[a-z]` any
+/// character of a class and `[!abc]` any character not in it. Any other character,
+/// including a `]` first in a class, matches itself.
+pub(crate) struct Pattern {
+    tokens: Vec<Token>,
+}
+
+enum Token {
+    Char(char),
+    AnyChar,
+    AnySequence,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Pattern {
+    /// Compiles a pattern, failing with `InvalidPattern` on an unclosed or empty class.
+    pub(crate) fn new(pattern: &str) -> Result<Pattern> {
+        let invalid = |reason: &str| KvsError::InvalidPattern(format!("{pattern:?}: {reason}"));
+
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            match chars[i] {
+                '?' => tokens.push(Token::AnyChar),
+                '*' => {
+                    // consecutive `*` match the same as a single one
+                    if !matches!(tokens.last(), Some(Token::AnySequence)) {
+                        tokens.push(Token::AnySequence);
+                    }
+                }
+                '[' => {
+                    let start = i;
+                    i += 1;
+
+                    let negated = chars.get(i) == Some(&'!');
+                    if negated {
+                        i += 1;
+                    }
+
+                    let mut ranges = Vec::new();
+                    loop {
+                        let c = match chars.get(i) {
+                            None => return Err(invalid(&format!("unclosed class at {start}"))),
+                            Some(']') if !ranges.is_empty() => break,
+                            Some(&c) => c,
+                        };
+
+                        match chars.get(i + 1..i + 3) {
+                            Some(&['-', end]) if end != ']' => {
+                                if end < c {
+                                    return Err(invalid(&format!("empty range {c}-{end}")));
+                                }
+                                ranges.push((c, end));
+                                i += 3;
+                            }
+                            _ => {
+                                ranges.push((c, c));
+                                i += 1;
+                            }
+                        }
+                    }
+
+                    tokens.push(Token::Class { negated, ranges });
+                }
+                c => tokens.push(Token::Char(c)),
+            }
+            i += 1;
+        }
+
+        Ok(Pattern { tokens })
+    }
+
+    /// Returns whether the pattern matches the whole of `s`.
+    pub(crate) fn matches(&self, s: &str) -> bool {
+        let chars: Vec<char> = s.chars().collect();
+
+        // where to resume after the last `*` if the rest fails to match: the token after
+        // it and the character it should swallow next
+        let mut backtrack = None;
+        let (mut t, mut c) = (0, 0);
+
+        while c < chars.len() {
+            match self.tokens.get(t) {
+                Some(Token::AnySequence) => {
+                    backtrack = Some((t + 1, c));
+                    t += 1;
+                    continue;
+                }
+                Some(token) if token.matches(chars[c]) => {
+                    t += 1;
+                    c += 1;
+                    continue;
+                }
+                _ => {}
+            }
+
+            match backtrack {
+                None => return false,
+                Some((after_star, swallowed)) => {
+                    backtrack = Some((after_star, swallowed + 1));
+                    t = after_star;
+                    c = swallowed + 1;
+                }
+            }
+        }
+
+        self.tokens[t..]
+            .iter()
+            .all(|token| matches!(token, Token::AnySequence))
+    }
+}
+
+impl Token {
+    /// Returns whether a token other than `AnySequence` matches a character.
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            Token::Char(expected) => c == expected,
+            Token::AnyChar => true,
+            Token::AnySequence => false,
+            Token::Class {
+                negated,
+                ref ranges,
+            } => {
+                ranges
+                    .iter()
+                    .any(|&(start, end)| (start..=end).contains(&c))
+                    != negated
+            }
+        }
+    }
+}
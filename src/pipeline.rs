@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use crate::{KvStore, Result, WriteBatch};
+
+/// Operations queued on a store and executed in order, see `KvStore::pipeline`.
+///
+/// All writes are written as one `WriteBatch`, with a single flush, once every read has
+/// been answered. Reads see the writes queued before them. This is not a transaction:
+/// if writing fails, reads were already answered and there is no rollback of writes that
+/// made it to disk before the failure.
+pub struct Pipeline<'a> {
+    store: &'a mut KvStore,
+    ops: Vec<PipelineOp>,
+}
+
+/// An operation queued in a `Pipeline`.
+enum PipelineOp {
+    Set { key: String, value: String },
+    Get { key: String },
+    Remove { key: String },
+}
+
+/// The result of an operation of a `Pipeline`, in the order the operations were queued.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpResult {
+    /// The key was set.
+    Set,
+
+    /// The value of the key, if it exists.
+    Get(Option<String>),
+
+    /// Whether the key existed and was removed.
+    Remove(bool),
+}
+
+impl<'a> Pipeline<'a> {
+    /// Creates an empty pipeline on `store`.
+    pub(crate) fn new(store: &'a mut KvStore) -> Pipeline<'a> {
+        Pipeline {
+            store,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Queues setting the value of a string key to a string.
+    pub fn set(mut self, key: String, value: String) -> Pipeline<'a> {
+        self.ops.push(PipelineOp::Set { key, value });
+        self
+    }
+
+    /// Queues getting the string value of a given string key.
+    pub fn get(mut self, key: String) -> Pipeline<'a> {
+        self.ops.push(PipelineOp::Get { key });
+        self
+    }
+
+    /// Queues removing a given key.
+    ///
+    /// Removing a key that does not exist by then is a no-op.
+    pub fn remove(mut self, key: String) -> Pipeline<'a> {
+        self.ops.push(PipelineOp::Remove { key });
+        self
+    }
+
+    /// Executes the queued operations and returns a result for each.
+    pub fn execute(self) -> Result<Vec<OpResult>> {
+        let mut results = Vec::with_capacity(self.ops.len());
+        let mut batch = WriteBatch::new();
+        // values of keys as of the writes queued so far, `None` for removed keys
+        let mut staged: HashMap<String, Option<String>> = HashMap::new();
+
+        for op in self.ops {
+            match op {
+                PipelineOp::Set { key, value } => {
+                    let key = self.store.normalize_owned(key);
+                    staged.insert(key.clone(), Some(value.clone()));
+                    batch.set(key, value);
+                    results.push(OpResult::Set);
+                }
+
+                PipelineOp::Get { key } => {
+                    let key = self.store.normalize_owned(key);
+                    let value = match staged.get(&key) {
+                        Some(value) => value.clone(),
+                        None => self.store.get(&key)?,
+                    };
+                    results.push(OpResult::Get(value));
+                }
+
+                PipelineOp::Remove { key } => {
+                    let key = self.store.normalize_owned(key);
+                    let exists = match staged.insert(key.clone(), None) {
+                        Some(value) => value.is_some(),
+                        None => self.store.contains_key(&key),
+                    };
+                    if exists {
+                        batch.remove(key);
+                    }
+                    results.push(OpResult::Remove(exists));
+                }
+            }
+        }
+
+        self.store.write_batch(batch)?;
+
+        Ok(results)
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::kv::segment_reader;
+use crate::{KvStoreOptions, KvsError, Result};
+
+/// The segments of a store and readers for them.
+///
+/// With `max_open_readers` set, only that many readers are kept open at once; the least
+/// recently used one is closed to make room and reopened when it is needed again. The
+/// reader of the newest segment, the active one of a writable store, is never closed.
+pub(crate) struct Readers {
+    path: PathBuf,
+    options: KvStoreOptions,
+    segments: BTreeMap<u64, Option<OpenReader>>,
+    open: usize,
+    // incremented on every use, to tell the least recently used reader
+    clock: u64,
+}
+
+/// An open reader and when it was last used.
+struct OpenReader {
+    reader: BufReader<File>,
+    last_used: u64,
+}
+
+impl Readers {
+    /// Creates an empty set of segments of the store at `path`.
+    pub(crate) fn new(path: &Path, options: &KvStoreOptions) -> Readers {
+        Readers {
+            path: path.to_owned(),
+            options: options.clone(),
+            segments: BTreeMap::new(),
+            open: 0,
+            clock: 0,
+        }
+    }
+
+    /// Returns whether the segment is part of the store.
+    pub(crate) fn contains(&self, segment: u64) -> bool {
+        self.segments.contains_key(&segment)
+    }
+
+    /// Returns the segments of the store in ascending order.
+    pub(crate) fn segments(&self) -> Vec<u64> {
+        self.segments.keys().copied().collect()
+    }
+
+    /// Returns the number of segments.
+    pub(crate) fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Returns the number of open readers.
+    pub(crate) fn open_len(&self) -> usize {
+        self.open
+    }
+
+    /// Adds a segment with an open reader.
+    pub(crate) fn insert(&mut self, segment: u64, reader: BufReader<File>) {
+        self.clock += 1;
+
+        let open = OpenReader {
+            reader,
+            last_used: self.clock,
+        };
+        if let Some(None) | None = self.segments.insert(segment, Some(open)) {
+            self.open += 1;
+        }
+
+        self.evict(segment);
+    }
+
+    /// Adds a segment without opening it until it is read.
+    pub(crate) fn insert_closed(&mut self, segment: u64) {
+        self.segments.entry(segment).or_insert(None);
+    }
+
+    /// Removes a segment, closing its reader.
+    pub(crate) fn remove(&mut self, segment: u64) {
+        if let Some(Some(_)) = self.segments.remove(&segment) {
+            self.open -= 1;
+        }
+    }
+
+    /// Returns the reader of a segment, reopening it if it was closed.
+    ///
+    /// Returns `None` if the segment is not part of the store.
+    pub(crate) fn get_mut(&mut self, segment: u64) -> Result<Option<&mut BufReader<File>>> {
+        match self.segments.get(&segment) {
+            None => return Ok(None),
+            Some(Some(_)) => {}
+            Some(None) => {
+                let reader = self.open(segment)?;
+                self.insert(segment, reader);
+            }
+        }
+
+        self.clock += 1;
+
+        let open = self
+            .segments
+            .get_mut(&segment)
+            .and_then(Option::as_mut)
+            .expect("segment reader is open");
+        open.last_used = self.clock;
+
+        Ok(Some(&mut open.reader))
+    }
+
+    /// Returns the reader of a segment, opening it even if it is not part of the store.
+    ///
+    /// This is for segments the index points into, which must exist: a segment missing from
+    /// the set is opened and added to it, and only a missing file fails, with
+    /// `MissingSegment`, instead of passing the segment off as absent.
+    pub(crate) fn get_or_open(&mut self, segment: u64) -> Result<&mut BufReader<File>> {
+        if !self.contains(segment) {
+            let reader = self.open(segment)?;
+            self.insert(segment, reader);
+        }
+
+        Ok(self.get_mut(segment)?.expect("segment was just added"))
+    }
+
+    /// Opens a reader for a segment, failing with `MissingSegment` if its file is gone.
+    fn open(&self, segment: u64) -> Result<BufReader<File>> {
+        match segment_reader(&self.path, &self.options, segment) {
+            Err(KvsError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                Err(KvsError::MissingSegment(segment))
+            }
+            res => res,
+        }
+    }
+
+    /// Closes the least recently used readers over the limit, except those of `keep` and the newest segment.
+    fn evict(&mut self, keep: u64) {
+        let max = match self.options.max_open_readers {
+            None => return,
+            Some(max) => max,
+        };
+        let newest = self.segments.keys().next_back().copied();
+
+        while self.open > max {
+            let lru = self
+                .segments
+                .iter()
+                .filter(|&(&segment, _)| segment != keep && Some(segment) != newest)
+                .filter_map(|(&segment, open)| Some((open.as_ref()?.last_used, segment)))
+                .min();
+
+            match lru {
+                None => break,
+                Some((_, segment)) => {
+                    self.segments.insert(segment, None);
+                    self.open -= 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::hash::Index;
+use crate::kv::read_value;
+use crate::readers::Readers;
+use crate::{KvStoreOptions, Result};
+
+/// A frozen, read-only view of a store, see `KvStore::snapshot`.
+///
+/// Snapshots are cheap to clone and can be sent to other threads. Clones share their
+/// index and readers, so their reads take turns.
+#[derive(Clone)]
+pub struct Snapshot {
+    inner: Arc<Frozen>,
+}
+
+struct Frozen {
+    // the positions of the values, with blob references already followed
+    index: Index,
+    readers: Mutex<Readers>,
+    options: KvStoreOptions,
+    segments: Vec<u64>,
+    pins: Arc<Pins>,
+}
+
+impl Snapshot {
+    /// Freezes the given values, pinning the segments holding them.
+    pub(crate) fn new(
+        path: &Path,
+        options: &KvStoreOptions,
+        index: Index,
+        pins: Arc<Pins>,
+    ) -> Snapshot {
+        let mut segments: Vec<u64> = index.values().map(|position| position.segment).collect();
+        segments.sort_unstable();
+        segments.dedup();
+
+        pins.pin(&segments);
+
+        let mut readers = Readers::new(path, options);
+        for &segment in &segments {
+            readers.insert_closed(segment);
+        }
+
+        Snapshot {
+            inner: Arc::new(Frozen {
+                index,
+                readers: Mutex::new(readers),
+                options: options.clone(),
+                segments,
+                pins,
+            }),
+        }
+    }
+
+    /// Gets the string value of a given key as of taking the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let key = self.inner.options.normalize_key(key);
+        let position = match self.inner.index.get(key.as_ref()) {
+            None => return Ok(None),
+            Some(&position) => position,
+        };
+
+        let mut readers = self
+            .inner
+            .readers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        read_value(&mut readers, &self.inner.options, position)
+    }
+
+    /// Returns the number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.inner.index.len()
+    }
+
+    /// Returns whether the snapshot holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.inner.index.is_empty()
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keys and values are deliberately left out, they may be sensitive
+        f.debug_struct("Snapshot")
+            .field("keys", &self.inner.index.len())
+            .field("segments", &self.inner.segments)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Frozen {
+    fn drop(&mut self) {
+        // nobody is left to report a failure to, the next compaction of a reopened
+        // store takes care of leftover segments
+        let _ = self.pins.unpin(&self.segments);
+    }
+}
+
+/// Counts the snapshots pinning each segment of a store.
+///
+/// Segments removed from the store while pinned keep their files until the last
+/// snapshot pinning them is dropped.
+#[derive(Default)]
+pub(crate) struct Pins {
+    state: Mutex<PinState>,
+}
+
+#[derive(Default)]
+struct PinState {
+    counts: HashMap<u64, usize>,
+    // files of removed segments still pinned
+    removed: HashMap<u64, PathBuf>,
+}
+
+impl Pins {
+    /// Removes the file of a segment removed from the store, or defers it while pinned.
+    pub(crate) fn remove_file(&self, segment: u64, file: PathBuf) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if state.counts.contains_key(&segment) {
+            state.removed.insert(segment, file);
+            return Ok(());
+        }
+
+        fs::remove_file(file)
+    }
+
+    fn pin(&self, segments: &[u64]) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        for &segment in segments {
+            *state.counts.entry(segment).or_insert(0) += 1;
+        }
+    }
+
+    /// Drops a pin of each segment, removing the files of removed segments no longer pinned.
+    fn unpin(&self, segments: &[u64]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut res = Ok(());
+        for &segment in segments {
+            let count = state.counts.get_mut(&segment).expect("segment is pinned");
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+
+            state.counts.remove(&segment);
+            if let Some(file) = state.removed.remove(&segment) {
+                res = res.and(fs::remove_file(file));
+            }
+        }
+
+        res
+    }
+}
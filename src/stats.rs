@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Operation counts of a store since it was opened or its stats were last reset,
+/// see `KvStore::op_counters`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpCounters {
+    /// Number of keys set, including those set as part of a batch.
+    pub sets: u64,
+
+    /// Number of gets that found their key.
+    pub get_hits: u64,
+
+    /// Number of gets that did not find their key.
+    pub get_misses: u64,
+
+    /// Number of keys removed, including those removed as part of a batch.
+    pub removes: u64,
+
+    /// Number of compactions, whether triggered automatically or by `compact`.
+    pub compactions: u64,
+}
+
+/// Monotonic operation counters.
+///
+/// The counters are only ever read as a whole for reporting, so relaxed ordering suffices.
+#[derive(Default)]
+pub(crate) struct Counters {
+    sets: AtomicU64,
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+    removes: AtomicU64,
+    compactions: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn set(&self) {
+        self.sets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self, hit: bool) {
+        let counter = if hit {
+            &self.get_hits
+        } else {
+            &self.get_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn remove(&self) {
+        self.removes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn compaction(&self) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current counts.
+    pub(crate) fn snapshot(&self) -> OpCounters {
+        OpCounters {
+            sets: self.sets.load(Ordering::Relaxed),
+            get_hits: self.get_hits.load(Ordering::Relaxed),
+            get_misses: self.get_misses.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sets all counts back to zero.
+    pub(crate) fn reset(&self) {
+        for counter in [
+            &self.sets,
+            &self.get_hits,
+            &self.get_misses,
+            &self.removes,
+            &self.compactions,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::io::{self, BufRead, Cursor, Read};
+
+/// Reads the decoded value of a `Set` or `Blob` command straight from the log.
+///
+/// The command is read from `R`, which must end where the command does. Only the
+/// command's framing and key are buffered; the JSON string holding the value is unescaped
+/// as it is read, so values are never held in memory as a whole.
+pub(crate) enum ValueReader<R> {
+    Streamed(JsonString<R>),
+    /// A value that had to be decoded in memory first, e.g. to decrypt it.
+    Buffered(Cursor<Vec<u8>>),
+}
+
+impl<R: BufRead> ValueReader<R> {
+    /// Skips `reader` forward to the value of the command and returns a reader for it.
+    pub(crate) fn new(mut reader: R) -> io::Result<ValueReader<R>> {
+        skip_to_value(&mut reader)?;
+
+        Ok(ValueReader::Streamed(JsonString {
+            reader,
+            pending: [0; 4],
+            pending_start: 0,
+            pending_end: 0,
+            done: false,
+        }))
+    }
+}
+
+impl<R: BufRead> Read for ValueReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ValueReader::Streamed(string) => string.read(buf),
+            ValueReader::Buffered(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+/// The contents of a JSON string, unescaped as they are read.
+pub(crate) struct JsonString<R> {
+    reader: R,
+    // the UTF-8 bytes of an escaped character not yet returned
+    pending: [u8; 4],
+    pending_start: usize,
+    pending_end: usize,
+    // whether the closing quote was read
+    done: bool,
+}
+
+impl<R: BufRead> Read for JsonString<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+
+        while n < buf.len() {
+            if self.pending_start < self.pending_end {
+                buf[n] = self.pending[self.pending_start];
+                self.pending_start += 1;
+                n += 1;
+                continue;
+            }
+
+            if self.done {
+                break;
+            }
+
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                return Err(truncated());
+            }
+
+            // copy plain bytes up to the next quote or escape as they are
+            let wanted = available.len().min(buf.len() - n);
+            let plain = available[..wanted]
+                .iter()
+                .position(|&b| b == b'"' || b == b'\\')
+                .unwrap_or(wanted);
+
+            buf[n..n + plain].copy_from_slice(&available[..plain]);
+            n += plain;
+            self.reader.consume(plain);
+
+            if plain == wanted {
+                continue;
+            }
+
+            match next_byte(&mut self.reader)? {
+                b'"' => self.done = true,
+                _ => {
+                    let c = read_escape(&mut self.reader)?;
+                    self.pending_end = c.encode_utf8(&mut self.pending).len();
+                    self.pending_start = 0;
+                }
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+/// Skips `reader` past the opening quote of the `value` field of a command.
+///
+/// Strings are read whole, so a key containing `"value":` is not mistaken for the field.
+fn skip_to_value<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    let mut name = Vec::new();
+
+    loop {
+        if next_byte(reader)? != b'"' {
+            continue;
+        }
+
+        name.clear();
+        read_string(reader, &mut name)?;
+
+        if name != b"value" {
+            continue;
+        }
+
+        if skip_whitespace(reader)? == b':' && skip_whitespace(reader)? == b'"' {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the rest of a JSON string, after its opening quote, into `out`.
+fn read_string<R: BufRead>(reader: &mut R, out: &mut Vec<u8>) -> io::Result<()> {
+    loop {
+        match next_byte(reader)? {
+            b'"' => return Ok(()),
+            b'\\' => {
+                let c = read_escape(reader)?;
+                out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            b => out.push(b),
+        }
+    }
+}
+
+/// Reads an escape sequence, after its backslash, and returns the character it stands for.
+fn read_escape<R: BufRead>(reader: &mut R) -> io::Result<char> {
+    let c = match next_byte(reader)? {
+        b'"' => '"',
+        b'\\' => '\\',
+        b'/' => '/',
+        b'b' => '\u{8}',
+        b'f' => '\u{c}',
+        b'n' => '\n',
+        b'r' => '\r',
+        b't' => '\t',
+        b'u' => {
+            let unit = read_hex4(reader)?;
+
+            // characters outside the basic plane are escaped as a surrogate pair
+            let code = if (0xD800..0xDC00).contains(&unit) {
+                if next_byte(reader)? != b'\\' || next_byte(reader)? != b'u' {
+                    return Err(invalid("lone surrogate in value"));
+                }
+                let low = read_hex4(reader)?;
+                if !(0xDC00..0xE000).contains(&low) {
+                    return Err(invalid("lone surrogate in value"));
+                }
+                0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+            } else {
+                unit
+            };
+
+            char::from_u32(code).ok_or_else(|| invalid("lone surrogate in value"))?
+        }
+        _ => return Err(invalid("invalid escape in value")),
+    };
+
+    Ok(c)
+}
+
+/// Reads the four hex digits of a `\u` escape.
+fn read_hex4<R: BufRead>(reader: &mut R) -> io::Result<u32> {
+    let mut code = 0;
+
+    for _ in 0..4 {
+        let digit = (next_byte(reader)? as char)
+            .to_digit(16)
+            .ok_or_else(|| invalid("invalid escape in value"))?;
+        code = code * 16 + digit;
+    }
+
+    Ok(code)
+}
+
+/// Returns the next byte that is not JSON whitespace.
+fn skip_whitespace<R: BufRead>(reader: &mut R) -> io::Result<u8> {
+    loop {
+        match next_byte(reader)? {
+            b' ' | b'\t' | b'\n' | b'\r' => continue,
+            b => return Ok(b),
+        }
+    }
+}
+
+/// Reads one byte, failing if the command ends before it.
+fn next_byte<R: BufRead>(reader: &mut R) -> io::Result<u8> {
+    let b = *reader.fill_buf()?.first().ok_or_else(truncated)?;
+    reader.consume(1);
+
+    Ok(b)
+}
+
+fn truncated() -> io::Error {
+    invalid("command ends before its value")
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
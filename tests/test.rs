@@ -1,8 +1,16 @@
+// many tests predate `get` taking borrowed keys and still pass owned ones
+#![allow(clippy::unnecessary_to_owned)]
+
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    CommandPositionPublic, CompactionPolicy, CompactionResult, CorruptionPolicy, Durability,
+    GetStatus, KvStore, KvStoreOptions, KvsError, MapAdapter, OpCounters, OpResult, PublicCommand,
+    Result, VerifyReport, WriteBatch,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
 use std::process::Command;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +25,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +36,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +49,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +62,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -72,7 +80,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +88,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -100,7 +108,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +116,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +129,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +144,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +165,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +180,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -298,4 +306,3382 @@ fn compaction() -> Result<()> {
     }
 
     panic!("No compaction detected");
-}
\ No newline at end of file
+}
+
+// Should append to existing and missing values.
+#[test]
+fn append_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.append("key1".to_owned(), "foo")?, 3);
+    assert_eq!(store.append("key1".to_owned(), "bar")?, 6);
+    assert_eq!(store.get("key1".to_owned())?, Some("foobar".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("foobar".to_owned()));
+
+    Ok(())
+}
+
+// Segments should use the configured file naming and ignore foreign files.
+#[test]
+fn custom_segment_naming() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().prefix("kvs-").extension(".kvslog");
+
+    // a file of another tool that looks like a default segment
+    std::fs::write(temp_dir.path().join("1.log"), "not a kvs segment")?;
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert!(temp_dir.path().join("kvs-1.kvslog").exists());
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("1.log"))?,
+        "not a kvs segment"
+    );
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should estimate compaction without changing the store.
+#[test]
+fn estimate_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let live = store.estimate_compaction().live_bytes;
+    assert_eq!(store.estimate_compaction().reclaimable_bytes, 0);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let estimate = store.estimate_compaction();
+    assert_eq!(estimate.reclaimable_bytes, live);
+    assert_eq!(estimate.live_bytes, live);
+    assert_eq!(estimate.segments_involved, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Namespaces should partition keys.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "plain".to_owned())?;
+    store.set_ns("users", "key1".to_owned(), "user".to_owned())?;
+    store.set_ns("groups", "key1".to_owned(), "group".to_owned())?;
+    store.set_ns("groups", "key2".to_owned(), "group2".to_owned())?;
+    store.remove_ns("groups", "key2".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("plain".to_owned()));
+    assert_eq!(
+        store.get_ns("", "key1".to_owned())?,
+        Some("plain".to_owned())
+    );
+    assert_eq!(
+        store.get_ns("users", "key1".to_owned())?,
+        Some("user".to_owned())
+    );
+    assert_eq!(store.get_ns("groups", "key2".to_owned())?, None);
+    assert!(store.remove_ns("users", "key2".to_owned()).is_err());
+
+    assert_eq!(store.keys_ns("")?, vec!["key1".to_owned()]);
+    assert_eq!(
+        store.scan_ns("groups")?,
+        vec![("key1".to_owned(), "group".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Namespaced keys should never collide with keys of another namespace.
+#[test]
+fn namespace_separator_collision() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_ns("a", "b".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        store.set_ns("", "a\0b".to_owned(), "value2".to_owned()),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+    assert!(matches!(
+        store.set_ns("a\0b", "c".to_owned(), "value3".to_owned()),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+    assert!(matches!(
+        store.get_ns("", "a\0b".to_owned()),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+    assert!(matches!(
+        store.remove_ns("a\0", "b".to_owned()),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+    assert!(matches!(
+        store.keys_ns("a\0"),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+    assert!(matches!(
+        store.scan_ns("a\0"),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+
+    assert_eq!(
+        store.get_ns("a", "b".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.keys_ns("")?, Vec::<String>::new());
+
+    Ok(())
+}
+
+// Should report the state recovered while opening.
+#[test]
+fn open_with_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    let uncompacted = store.estimate_compaction().reclaimable_bytes;
+    drop(store);
+
+    std::fs::write(temp_dir.path().join("notes.txt"), "")?;
+
+    let (mut store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(report.segments_loaded, 1);
+    assert_eq!(report.records_replayed, 3);
+    assert_eq!(report.uncompacted_bytes, uncompacted);
+    assert_eq!(report.records_skipped, 0);
+    assert_eq!(
+        report.unexpected_files,
+        vec![temp_dir.path().join("notes.txt")]
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Debug and Display should summarize the store without exposing values.
+#[test]
+fn debug_and_display() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "secret".to_owned())?;
+
+    let debug = format!("{:?}", store);
+    assert!(debug.contains("keys: 1"));
+    assert!(!debug.contains("secret"));
+
+    let display = store.to_string();
+    assert!(display.contains("1 keys"));
+    assert!(!display.contains("secret"));
+
+    Ok(())
+}
+
+// Compaction should not write into segment files left behind on disk.
+#[test]
+fn compaction_skips_orphan_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // an orphan segment, as if left behind by a crashed compaction
+    std::fs::write(temp_dir.path().join("2.log"), "")?;
+
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(std::fs::read(temp_dir.path().join("2.log"))?.len(), 0);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should insert raw positions only for known segments.
+#[test]
+fn index_insert_raw() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = store.estimate_compaction().live_bytes;
+
+    store.index_insert_raw("alias".to_owned(), 1, 0, len)?;
+    assert_eq!(store.get("alias".to_owned())?, Some("value1".to_owned()));
+
+    assert!(matches!(
+        store.index_insert_raw("key2".to_owned(), 42, 0, len),
+        Err(KvsError::MissingSegment(42))
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should dump every command of a segment, including stale ones.
+#[test]
+fn dump_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = store.estimate_compaction().live_bytes;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let commands = store.dump_segment(1)?;
+    assert_eq!(
+        commands,
+        vec![
+            (
+                0,
+                PublicCommand::Set {
+                    key: "key1".to_owned(),
+                    value: "value1".to_owned()
+                }
+            ),
+            (
+                len,
+                PublicCommand::Set {
+                    key: "key1".to_owned(),
+                    value: "value2".to_owned()
+                }
+            ),
+            (
+                2 * len,
+                PublicCommand::Remove {
+                    key: "key1".to_owned()
+                }
+            ),
+        ]
+    );
+
+    assert!(matches!(
+        store.dump_segment(42),
+        Err(KvsError::MissingSegment(42))
+    ));
+
+    Ok(())
+}
+
+// Should recover to a consistent state after crashes at write boundaries.
+#[cfg(feature = "fault-injection")]
+#[test]
+fn crash_recovery() -> Result<()> {
+    use kvs::{FaultInjector, FaultPoint};
+    use std::io;
+
+    struct Crash {
+        point: Option<FaultPoint>,
+        partial: Option<usize>,
+    }
+
+    impl FaultInjector for Crash {
+        fn inject(&mut self, point: FaultPoint) -> io::Result<()> {
+            match self.point {
+                Some(crash) if crash == point => Err(io::Error::other("crash")),
+                _ => Ok(()),
+            }
+        }
+
+        fn partial_write(&mut self, len: usize) -> Option<usize> {
+            self.partial.map(|partial| partial.min(len - 1))
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // torn write
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_fault_injector(Crash {
+        point: None,
+        partial: Some(5),
+    });
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    drop(store);
+
+    let (mut store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(report.records_skipped, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // crash after a complete write, the command is durable
+    store.set_fault_injector(Crash {
+        point: Some(FaultPoint::AfterWrite),
+        partial: None,
+    });
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // crash before stale segments are removed
+    store.set_fault_injector(Crash {
+        point: Some(FaultPoint::AfterCompactionCopy),
+        partial: None,
+    });
+    assert!(store.compact().is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Identical values should be stored once in dedup mode.
+#[test]
+fn dedup_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().dedup_values(true);
+    let value = "x".repeat(10_000);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert!(std::fs::metadata(temp_dir.path().join("1.log"))?.len() < 2 * value.len() as u64);
+
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+
+    // Open from disk again, compact and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+
+    // the last reference is gone, so the blob is garbage
+    store.remove("key2".to_owned())?;
+    assert!(store.estimate_compaction().reclaimable_bytes > value.len() as u64);
+
+    Ok(())
+}
+
+// Should track when keys were last written, across reopens.
+#[test]
+fn last_modified() -> Result<()> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // a segment written before write times were recorded
+    std::fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"old","value":"value"}}"#,
+    )?;
+
+    let before = SystemTime::now() - std::time::Duration::from_millis(1);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let modified = store.last_modified("key1").expect("key1 was written");
+    assert!(modified >= before && modified <= SystemTime::now());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_modified("key1"), Some(modified));
+    assert_eq!(store.last_modified("old"), Some(UNIX_EPOCH));
+    assert_eq!(store.get("old".to_owned())?, Some("value".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.last_modified("key1"), None);
+
+    Ok(())
+}
+
+// Whitespace between commands should not throw off command positions.
+#[test]
+fn load_segment_with_whitespace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    let second = r#"{"Set":{"key":"key2","value":"value2"}}"#;
+    std::fs::write(
+        temp_dir.path().join("1.log"),
+        format!(" {}\n\n{}\n", first, second),
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.estimate_compaction().live_bytes,
+        (first.len() + second.len()) as u64
+    );
+
+    let offsets: Vec<u64> = store
+        .dump_segment(1)?
+        .into_iter()
+        .map(|(offset, _)| offset)
+        .collect();
+    assert_eq!(offsets, vec![1, first.len() as u64 + 3]);
+
+    // compaction copies the exact command bytes
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Index memory estimates should grow with the number and length of keys.
+#[test]
+fn estimated_index_bytes() {
+    assert_eq!(KvStore::estimated_index_bytes(0, 16), 0);
+
+    let small = KvStore::estimated_index_bytes(1_000, 16);
+    assert!(small > 1_000 * (3 * 16 + 24));
+    assert!(KvStore::estimated_index_bytes(1_000, 32) > small);
+    assert!(KvStore::estimated_index_bytes(1_000_000, 16) > 1_000 * small / 2);
+}
+
+// Batched writes should only become visible once written.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value2".to_owned())
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key2".to_owned())
+        .remove("missing".to_owned())
+        .set("key3".to_owned(), "value3".to_owned());
+    assert_eq!(batch.len(), 5);
+
+    // staged writes are not visible
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+
+    Ok(())
+}
+
+// Synced writes should behave like flushed ones.
+#[test]
+fn durability_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().durability(Durability::Sync);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Writes should wait for the background commit, relaxed ones should not, and dropping
+// the store should commit what is left without waiting for the interval.
+#[test]
+fn commit_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let options = KvStoreOptions::new().commit_interval(Duration::from_millis(10));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let started = Instant::now();
+    let options = KvStoreOptions::new().commit_interval(Duration::from_secs(3600));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set_relaxed("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(started.elapsed() < Duration::from_secs(60));
+
+    // Open from disk again and check persistent data.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot should keep its view and segments through writes and compactions, in any
+// thread, and release the segments once dropped.
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment_exists = |segment: u64| temp_dir.path().join(format!("{segment}.log")).exists();
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    assert!(segment_exists(1));
+
+    let clone = snapshot.clone();
+    let values = std::thread::spawn(move || -> Result<_> {
+        Ok((clone.get("key1")?, clone.get("key2")?, clone.get("key3")?))
+    })
+    .join()
+    .expect("snapshot reader panicked")?;
+    assert_eq!(
+        values,
+        (Some("value1".to_owned()), Some("value2".to_owned()), None)
+    );
+    assert_eq!(snapshot.len(), 2);
+
+    drop(snapshot);
+    assert!(!segment_exists(1));
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    Ok(())
+}
+
+// Should recommend compaction once enough stale data built up.
+#[test]
+fn should_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1024);
+
+    store.set("key1".to_owned(), value.clone())?;
+    assert!(!store.should_compact());
+
+    // compaction happens on the write that crosses the threshold
+    let mut crossed = false;
+    for _ in 0..2000 {
+        let before = store.estimate_compaction().reclaimable_bytes;
+        store.set("key1".to_owned(), value.clone())?;
+        if store.estimate_compaction().reclaimable_bytes < before {
+            crossed = true;
+            break;
+        }
+        assert!(!store.should_compact());
+    }
+    assert!(crossed);
+    assert!(!store.should_compact());
+
+    // reading right after a compaction
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Pre-sizing the index should not change the store contents.
+#[test]
+fn expected_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().expected_keys(10_000);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Read-only stores should see the writer's changes after a refresh.
+#[test]
+fn read_only_refresh() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(reader.compact(), Err(KvsError::ReadOnly)));
+
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    reader.refresh()?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    writer.remove("key1".to_owned())?;
+    writer.compact()?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The index snapshot should be serializable and self-describing.
+#[test]
+fn index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = store.estimate_compaction().live_bytes / 2;
+
+    let snapshot = store.index_snapshot();
+    assert_eq!(snapshot.keys().collect::<Vec<_>>(), vec!["key1", "key2"]);
+    assert_eq!(
+        snapshot["key1"],
+        CommandPositionPublic {
+            segment: 1,
+            offset: len,
+            len,
+        }
+    );
+
+    let json = serde_json::to_string(&snapshot)?;
+    assert!(json.contains(r#""key2":{"segment":1,"offset":0,"len":"#));
+
+    Ok(())
+}
+
+// Flushing should persist writes and be a no-op for read-only stores.
+#[test]
+fn flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    reader.flush()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should lend values to a closure, including escaped and deduplicated ones.
+#[test]
+fn with_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().dedup_values(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "quoted \"value\"".to_owned())?;
+
+    assert_eq!(store.with_value("key1".to_owned(), |v| v.len())?, Some(6));
+    assert_eq!(
+        store.with_value("key2".to_owned(), |v| v.to_owned())?,
+        Some("quoted \"value\"".to_owned())
+    );
+    assert_eq!(store.with_value("key3".to_owned(), |v| v.len())?, None);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        store.with_value("key1".to_owned(), |v| v == "value1")?,
+        Some(true)
+    );
+    assert_eq!(
+        store.with_value("key3".to_owned(), |v| v == "value3")?,
+        Some(true)
+    );
+
+    Ok(())
+}
+
+// Opening should start a new segment after the highest existing one.
+#[test]
+fn open_picks_next_segment() -> Result<()> {
+    let segment_files = |dir: &TempDir| {
+        let mut names: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+
+    // empty directory
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?;
+    assert_eq!(segment_files(&temp_dir), vec!["1.log"]);
+
+    // only a 0-based segment
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("0.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(segment_files(&temp_dir), vec!["0.log", "1.log"]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a gap in the numbering
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("3.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(segment_files(&temp_dir), vec!["3.log", "4.log"]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Operation counters should count writes, gets and compactions until reset.
+#[test]
+fn op_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key3".to_owned())?;
+    store.with_value("key1".to_owned(), |_| ())?;
+    store.with_value("key3".to_owned(), |_| ())?;
+    assert!(store.get_reader("key1")?.is_some());
+    store.remove("key2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key4".to_owned(), "value4".to_owned());
+    store.write_batch(batch)?;
+
+    store.compact()?;
+
+    assert_eq!(
+        store.op_counters(),
+        OpCounters {
+            sets: 3,
+            get_hits: 3,
+            get_misses: 2,
+            removes: 1,
+            compactions: 1,
+        }
+    );
+
+    store.reset_stats();
+    assert_eq!(store.op_counters(), OpCounters::default());
+
+    Ok(())
+}
+
+// Compaction should drop blobs no key refers to anymore, even with recent segments kept.
+#[cfg(feature = "fault-injection")]
+#[test]
+fn compaction_drops_orphaned_blobs() -> Result<()> {
+    use kvs::{FaultInjector, FaultPoint};
+    use std::io;
+
+    // tears the write after the given number of whole writes
+    struct TearAfter(Option<usize>);
+
+    impl FaultInjector for TearAfter {
+        fn inject(&mut self, _point: FaultPoint) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn partial_write(&mut self, len: usize) -> Option<usize> {
+            match self.0.as_mut() {
+                Some(0) => Some(len / 2),
+                Some(writes) => {
+                    *writes -= 1;
+                    None
+                }
+                None => None,
+            }
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .dedup_values(true)
+        .keep_recent_segments(1);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // the blob is written, but the key never refers to it
+    store.set_fault_injector(TearAfter(Some(1)));
+    assert!(store.set("key2".to_owned(), "orphaned".to_owned()).is_err());
+    store.set_fault_injector(TearAfter(None));
+
+    store.compact()?;
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let contents = std::fs::read_to_string(entry?.path())?;
+        assert!(!contents.contains("orphaned"));
+    }
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should leave the most recent segments as they are.
+#[test]
+fn compaction_keeps_recent_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().keep_recent_segments(1);
+    let segment_exists = |segment: u64| temp_dir.path().join(format!("{segment}.log")).exists();
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // the only segment is kept and sealed
+    store.compact()?;
+    assert!(segment_exists(1));
+    assert!(segment_exists(2));
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    // segment 1 is compacted into segment 3, segment 2 is kept
+    store.compact()?;
+    assert!(!segment_exists(1));
+    assert!(segment_exists(2));
+    assert!(segment_exists(3));
+    assert!(segment_exists(4));
+
+    for store in [
+        &mut store,
+        &mut KvStore::open_with_options(temp_dir.path(), options)?,
+    ] {
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    }
+
+    Ok(())
+}
+
+// The compaction estimate should leave out the segments kept by `keep_recent_segments`.
+#[test]
+fn estimate_compaction_keeps_recent_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .keep_recent_segments(1)
+        .auto_compact(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    // garbage in both segments, of which only the sealed one is compacted
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.set("key3".to_owned(), "value5".to_owned())?;
+
+    let estimate = store.estimate_compaction();
+    let result = store.compact()?;
+    assert_eq!(estimate.segments_involved, 1);
+    assert_eq!(estimate.reclaimable_bytes, result.reclaimed);
+    assert_eq!(estimate.live_bytes, result.bytes_after);
+    assert!(estimate.reclaimable_bytes > 0 && estimate.live_bytes > 0);
+
+    Ok(())
+}
+
+// Compaction should leave segments in place once their data is cold, and compact them
+// again once they churn.
+#[test]
+fn compaction_skips_cold_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().cold_after_compactions(1);
+    let segment_exists = |segment: u64| temp_dir.path().join(format!("{segment}.log")).exists();
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // segment 1 is compacted into segment 2
+    store.compact()?;
+    assert!(!segment_exists(1));
+    assert!(segment_exists(2));
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // segment 2 is cold, only segment 3 is compacted into segment 4
+    store.compact()?;
+    assert!(segment_exists(2));
+    assert!(!segment_exists(3));
+    assert!(segment_exists(4));
+
+    store.set("key1".to_owned(), "value4".to_owned())?;
+
+    // segment 2 churned, segment 4 is cold
+    store.compact()?;
+    assert!(!segment_exists(2));
+    assert!(segment_exists(4));
+
+    for store in [
+        &mut store,
+        &mut KvStore::open_with_options(temp_dir.path(), options)?,
+    ] {
+        assert_eq!(store.get("key1")?, Some("value4".to_owned()));
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// The compaction estimate should leave out cold segments, like compaction does.
+#[test]
+fn estimate_compaction_skips_cold_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .cold_after_compactions(1)
+        .auto_compact(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    // the compacted segment is cold, only the active one is compacted
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    let estimate = store.estimate_compaction();
+    let result = store.compact()?;
+    assert_eq!(estimate.segments_involved, 1);
+    assert_eq!(estimate.reclaimable_bytes, result.reclaimed);
+    assert_eq!(estimate.live_bytes, result.bytes_after);
+
+    Ok(())
+}
+
+// `verify` should confirm a healthy index and flag positions not matching the segments.
+#[test]
+fn verify_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+
+    let report = store.verify()?;
+    assert!(report.is_ok());
+    assert_eq!(report.checked, 3);
+
+    let snapshot = store.index_snapshot();
+    let (key1, key2) = (snapshot["key1"], snapshot["key2"]);
+
+    store.index_insert_raw("key1".to_owned(), key2.segment, key2.offset, key2.len)?;
+    store.index_insert_raw("key2".to_owned(), key2.segment, key2.offset, key2.len + 1)?;
+    store.index_insert_raw("key3".to_owned(), key1.segment, key1.offset + 1, key1.len)?;
+
+    assert_eq!(
+        store.verify()?,
+        VerifyReport {
+            checked: 3,
+            missing: vec!["key3".to_owned()],
+            mismatched: vec!["key1".to_owned()],
+            wrong_length: vec!["key2".to_owned()],
+        }
+    );
+
+    Ok(())
+}
+
+// Changing the compaction threshold should take effect on the next write.
+#[test]
+fn set_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_compaction_threshold(u64::MAX);
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("{:01000}", iter))?;
+    }
+    assert!(!store.should_compact());
+    assert_eq!(store.op_counters().compactions, 0);
+
+    store.set_compaction_threshold(1024);
+    assert!(store.should_compact());
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.op_counters().compactions, 1);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// The compaction threshold should not be tied to the store on disk.
+#[test]
+fn compaction_threshold_across_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_threshold(u64::MAX);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), format!("{:01000}", iter))?;
+    }
+    assert_eq!(store.op_counters().compactions, 0);
+    drop(store);
+
+    // reopened with the default threshold, the garbage written before is over it
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.should_compact());
+    for key in 0..10 {
+        let value = format!("{:01000}", 1990 + key);
+        assert_eq!(store.get(format!("key{key}"))?, Some(value));
+    }
+    store.set("key0".to_owned(), "value".to_owned())?;
+    assert_eq!(store.op_counters().compactions, 1);
+    drop(store);
+
+    // and a lowered threshold applies to garbage written before reopening too
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.should_compact());
+    store.set_compaction_threshold(0);
+    assert!(!store.should_compact());
+    store.set("key0".to_owned(), "value".to_owned())?;
+    assert_eq!(store.op_counters().compactions, 1);
+    assert_eq!(store.get("key0")?, Some("value".to_owned()));
+    assert_eq!(store.get("key9")?, Some(format!("{:01000}", 1999)));
+
+    Ok(())
+}
+
+// With automatic compaction disabled, writes should never compact, batches included.
+#[test]
+fn auto_compact_disabled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compact(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set_compaction_threshold(1024);
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{:0100}", iter))?;
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key".to_owned(), "value".to_owned());
+    store.write_batch(batch)?;
+    assert!(store.should_compact());
+    assert_eq!(store.op_counters().compactions, 0);
+
+    store.compact()?;
+    assert!(!store.should_compact());
+    assert_eq!(store.op_counters().compactions, 1);
+    assert_eq!(store.get("key")?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// `rename` should move a value to another key, also across reopening.
+#[test]
+fn rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    for options in [
+        KvStoreOptions::new(),
+        KvStoreOptions::new().prefix("dedup-").dedup_values(true),
+    ] {
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        assert!(store.rename("key1".to_owned(), "key2".to_owned())?);
+        assert!(store.rename("key2".to_owned(), "key2".to_owned())?);
+        assert!(!store.rename("key1".to_owned(), "key3".to_owned())?);
+
+        drop(store);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// Stores should work the same with any read buffer size.
+#[test]
+fn read_buffer_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    for size in [1, 1024 * 1024] {
+        let options = KvStoreOptions::new().read_buffer_size(size);
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set(format!("key{size}"), "value".to_owned())?;
+        store.compact()?;
+
+        drop(store);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get(format!("key{size}"))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A temporary store should remove its directory when dropped.
+#[cfg(feature = "tempfile")]
+#[test]
+fn open_temp() -> Result<()> {
+    let mut store = KvStore::open_temp()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let path = store.path().to_owned();
+    assert!(path.exists());
+
+    drop(store);
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+// Removing a missing key should report the key.
+#[test]
+fn remove_missing_key_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    match store.remove("key1".to_owned()) {
+        Err(err @ KvsError::KeyNotFound(_)) => {
+            assert_eq!(err.to_string(), "key not found: key1");
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    Ok(())
+}
+
+// Writes from many threads sharing a store should compact one at a time without losing data.
+#[test]
+fn concurrent_writes_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = std::sync::Arc::new(std::sync::Mutex::new(KvStore::open(temp_dir.path())?));
+    store.lock().unwrap().set_compaction_threshold(16 * 1024);
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for iter in 0..200 {
+                    let mut store = store.lock().unwrap();
+                    store.set(format!("key{}", iter % 10), format!("{thread}-{iter}"))?;
+                    store.set(format!("thread{thread}"), format!("{iter}"))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut store = store.lock().unwrap();
+    assert!(store.op_counters().compactions > 1);
+    assert!(store.verify()?.is_ok());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for thread in 0..8 {
+        assert_eq!(
+            store.get(format!("thread{thread}"))?,
+            Some("199".to_owned())
+        );
+    }
+    assert!(store.verify()?.is_ok());
+
+    Ok(())
+}
+
+// `first_key` and `last_key` should return the smallest and largest keys.
+#[test]
+fn first_last_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.first_key(), None);
+    assert_eq!(store.last_key(), None);
+
+    for key in ["b", "c", "a", "d"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("d".to_owned())?;
+
+    assert_eq!(store.first_key().map(String::as_str), Some("a"));
+    assert_eq!(store.last_key().map(String::as_str), Some("c"));
+
+    Ok(())
+}
+
+// A physical backup should restore to an identical store.
+#[test]
+fn physical_backup_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let mut backup = Vec::new();
+    let manifest = store.physical_backup(&mut backup)?;
+    assert_eq!(manifest.segments.len(), 2);
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restored =
+        KvStore::restore_physical(restore_dir.path(), &KvStoreOptions::new(), &mut &backup[..])?;
+    assert_eq!(restored, manifest);
+
+    let mut store = KvStore::open(restore_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // existing segments are never overwritten
+    assert!(KvStore::restore_physical(
+        restore_dir.path(),
+        &KvStoreOptions::new(),
+        &mut &backup[..]
+    )
+    .is_err());
+
+    Ok(())
+}
+
+// `get_status` should tell removed keys apart from ones never written, also after compaction.
+#[test]
+fn get_status() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    for compact in [false, true] {
+        if compact {
+            store.compact()?;
+            store = KvStore::open(temp_dir.path())?;
+        }
+
+        assert_eq!(
+            store.get_status("key1".to_owned())?,
+            GetStatus::Present("value1".to_owned())
+        );
+        assert_eq!(store.get_status("key2".to_owned())?, GetStatus::Removed);
+        assert_eq!(
+            store.get_status("key3".to_owned())?,
+            GetStatus::Present("value4".to_owned())
+        );
+        assert_eq!(
+            store.get_status("key4".to_owned())?,
+            GetStatus::NeverWritten
+        );
+    }
+
+    Ok(())
+}
+
+// Metadata files should neither be loaded as segments nor reported as unexpected.
+#[test]
+fn metadata_files_are_not_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().extension("snapshot");
+
+    for name in ["MANIFEST", "index.snapshot", ".lock"] {
+        std::fs::write(temp_dir.path().join(name), "metadata")?;
+    }
+    std::fs::create_dir(temp_dir.path().join("5.snapshot"))?;
+
+    let (mut store, report) = KvStore::open_with_report(temp_dir.path(), options)?;
+    assert_eq!(report.segments_loaded, 0);
+    assert_eq!(
+        report.unexpected_files,
+        vec![temp_dir.path().join("5.snapshot")]
+    );
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `set_from_reader` should store text read from a reader, or nothing if reading fails.
+#[test]
+fn set_from_reader() -> Result<()> {
+    use std::io::{self, Read};
+
+    // reads one byte at a time, then fails if asked to
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        fail: bool,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.bytes.split_first() {
+                Some((&byte, rest)) => {
+                    buf[0] = byte;
+                    self.bytes = rest;
+                    Ok(1)
+                }
+                None if self.fail => Err(io::Error::other("reader failed")),
+                None => Ok(0),
+            }
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value = "quotes \" and \\ slashes,\nnewlines and ünïcödé ✓ ".repeat(5000);
+    store.set_from_reader("key1".to_owned(), &mut value.as_bytes())?;
+    store.set_from_reader(
+        "key2".to_owned(),
+        &mut Trickle {
+            bytes: "ünïcödé ✓".as_bytes(),
+            fail: false,
+        },
+    )?;
+
+    assert!(store
+        .set_from_reader(
+            "key3".to_owned(),
+            &mut Trickle {
+                bytes: b"partial",
+                fail: true,
+            },
+        )
+        .is_err());
+    assert!(store
+        .set_from_reader("key3".to_owned(), &mut &b"invalid \xff"[..])
+        .is_err());
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+    assert_eq!(store.get("key2".to_owned())?, Some("ünïcödé ✓".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert!(store.verify()?.is_ok());
+
+    Ok(())
+}
+
+// `compact_segment` should move the live commands of one segment and remove it.
+#[test]
+fn compact_single_segment() -> Result<()> {
+    for options in [
+        KvStoreOptions::new(),
+        KvStoreOptions::new().dedup_values(true),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key3".to_owned(), "shared".to_owned())?;
+        drop(store);
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key4".to_owned(), "shared".to_owned())?;
+        store.remove("key3".to_owned())?;
+
+        store.compact_segment(1)?;
+        assert!(!temp_dir.path().join("1.log").exists());
+        assert!(matches!(
+            store.compact_segment(1),
+            Err(KvsError::MissingSegment(1))
+        ));
+
+        for store in [
+            &mut store,
+            &mut KvStore::open_with_options(temp_dir.path(), options)?,
+        ] {
+            assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+            assert_eq!(store.get_status("key2".to_owned())?, GetStatus::Removed);
+            assert_eq!(store.get("key3".to_owned())?, None);
+            assert_eq!(store.get("key4".to_owned())?, Some("shared".to_owned()));
+            assert!(store.verify()?.is_ok());
+        }
+    }
+
+    Ok(())
+}
+
+// Stale commands should be credited to the garbage of the segment they are in.
+#[test]
+fn per_segment_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment_len = |segment: u64| -> u64 {
+        std::fs::metadata(temp_dir.path().join(format!("{segment}.log")))
+            .unwrap()
+            .len()
+    };
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    let first = store.index_snapshot()["key1"].offset;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.segment_garbage(), [(1, first)].into());
+
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.segment_garbage(), [(1, segment_len(1))].into());
+
+    store.compact_segment(1)?;
+    assert_eq!(store.segment_garbage(), Default::default());
+
+    Ok(())
+}
+
+// Reads and removes should accept borrowed keys.
+#[test]
+fn borrowed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key2"));
+    assert!(store.contains_key_ct("key1"));
+    assert!(!store.contains_key_ct("key2"));
+    assert!(!store.contains_key_ct("key"));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    store.remove("key1")?;
+    assert!(!store.contains_key("key1"));
+    assert!(!store.contains_key_ct("key1"));
+    assert_eq!(store.get("key1")?, None);
+    assert!(matches!(
+        store.remove("key1"),
+        Err(KvsError::KeyNotFound(_))
+    ));
+
+    Ok(())
+}
+
+// Limiting open readers should close and reopen segments as they are read.
+#[test]
+fn max_open_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_open_readers(2);
+
+    // one segment per open
+    for iter in 0..5 {
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set(format!("key{iter}"), format!("value{iter}"))?;
+    }
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(format!("{:?}", store).contains("open_readers: 2"));
+
+    for _ in 0..2 {
+        for iter in 0..5 {
+            assert_eq!(
+                store.get(format!("key{iter}"))?,
+                Some(format!("value{iter}"))
+            );
+        }
+    }
+    assert!(format!("{:?}", store).contains("open_readers: 2"));
+
+    store.set("key0".to_owned(), "value5".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key0")?, Some("value5".to_owned()));
+    assert_eq!(store.get("key4")?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// `reopen` should replay what the writer appended since the last load.
+#[test]
+fn reopen_read_only() -> Result<()> {
+    use std::io::Write;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment = temp_dir.path().join("1.log");
+    std::fs::write(
+        &segment,
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","#,
+    )?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(reader.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2")?, None);
+
+    // the torn command is completed
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&segment)?
+        .write_all(br#""value":"value2"}}"#)?;
+    reader.reopen()?;
+    assert_eq!(reader.get("key2")?, Some("value2".to_owned()));
+
+    // a writer adds a segment
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key1")?;
+    reader.reopen()?;
+    assert_eq!(reader.get("key1")?, None);
+    assert_eq!(reader.get("key3")?, Some("value3".to_owned()));
+    assert!(reader.verify()?.is_ok());
+
+    // compaction replaces the segments
+    store.compact()?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    reader.reopen()?;
+    assert_eq!(reader.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(reader.get("key4")?, Some("value4".to_owned()));
+    assert!(reader.verify()?.is_ok());
+
+    Ok(())
+}
+
+// `compact` should report the bytes it rewrote and reclaimed.
+#[test]
+fn compaction_result() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2")?;
+
+    let before = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    let result = store.compact()?;
+    let after = std::fs::metadata(temp_dir.path().join("2.log"))?.len();
+
+    assert_eq!(result.bytes_before, before);
+    assert_eq!(result.bytes_after, after);
+    assert_eq!(result.reclaimed, before - after);
+    // key1 and the tombstone of key2
+    assert_eq!(result.records_rewritten, 2);
+
+    Ok(())
+}
+
+// `kvs compact` should print what it reclaimed and exit with zero.
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 records rewritten"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+
+    Ok(())
+}
+
+// Values should be encrypted on disk, and tampered values fail to decrypt.
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_values() -> Result<()> {
+    use kvs::ValueCipher;
+
+    // a toy cipher for testing: XOR with a key, authenticated by a trailing checksum
+    struct Xor(u8);
+
+    impl ValueCipher for Xor {
+        fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+            let mut ciphertext: Vec<u8> = plaintext.iter().map(|byte| byte ^ self.0).collect();
+            ciphertext.push(
+                plaintext
+                    .iter()
+                    .fold(0, |sum: u8, byte| sum.wrapping_add(*byte)),
+            );
+            ciphertext
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+            let (&checksum, ciphertext) = ciphertext.split_last()?;
+            let plaintext: Vec<u8> = ciphertext.iter().map(|byte| byte ^ self.0).collect();
+            let sum = plaintext
+                .iter()
+                .fold(0, |sum: u8, byte| sum.wrapping_add(*byte));
+            (sum == checksum).then_some(plaintext)
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().cipher(Xor(0x5a));
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "secret1".to_owned())?;
+    store.set("key2".to_owned(), "secret2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key1")?, Some("secret1".to_owned()));
+    assert_eq!(
+        store.with_value("key2".to_owned(), |value| value.len())?,
+        Some(7)
+    );
+    drop(store);
+
+    let segment = temp_dir.path().join("2.log");
+    let contents = std::fs::read_to_string(&segment)?;
+    assert!(!contents.contains("secret"));
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key2")?, Some("secret2".to_owned()));
+    drop(store);
+
+    // flip a hex digit of a value, keeping the length
+    let sealed = Xor(0x5a)
+        .encrypt(b"secret1")
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    let tampered = sealed.replacen('3', "4", 1);
+    std::fs::write(&segment, contents.replace(&sealed, &tampered))?;
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert!(matches!(store.get("key1"), Err(KvsError::Decryption)));
+    assert_eq!(store.get("key2")?, Some("secret2".to_owned()));
+
+    Ok(())
+}
+
+// Encrypted stores should refuse deduplication, whose blob hashes would leak which
+// values are equal.
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_refuses_dedup() -> Result<()> {
+    use kvs::ValueCipher;
+
+    struct Xor(u8);
+
+    impl ValueCipher for Xor {
+        fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+            plaintext.iter().map(|byte| byte ^ self.0).collect()
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+            Some(ciphertext.iter().map(|byte| byte ^ self.0).collect())
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().cipher(Xor(0x5a)).dedup_values(true);
+    assert!(KvStore::open_with_options(temp_dir.path(), options.clone()).is_err());
+    assert!(KvStore::open_read_only(temp_dir.path(), options).is_err());
+
+    let mut store =
+        KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().cipher(Xor(0x5a)))?;
+    store.set("key1".to_owned(), "secret".to_owned())?;
+    store.set("key2".to_owned(), "secret".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let contents = std::fs::read_to_string(entry?.path())?;
+        assert!(!contents.contains("hash"));
+    }
+
+    Ok(())
+}
+
+// `expect_get` should fail with the key if it does not exist.
+#[test]
+fn expect_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.expect_get("key1")?, "value1");
+    assert!(matches!(
+        store.expect_get("key2"),
+        Err(KvsError::KeyNotFound(key)) if key == "key2"
+    ));
+
+    Ok(())
+}
+
+// `scan_glob` should return the live keys matching a pattern and reject invalid patterns.
+#[test]
+fn scan_glob() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key in [
+        "session:1:active",
+        "session:2:idle",
+        "session:3:active",
+        "user:1",
+    ] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("session:3:active".to_owned())?;
+
+    assert_eq!(
+        store.scan_glob("session:*:active")?,
+        vec!["session:1:active".to_owned()]
+    );
+    assert_eq!(
+        store.scan_glob("session:[!1]:*")?,
+        vec!["session:2:idle".to_owned()]
+    );
+    assert_eq!(store.scan_glob("user:?")?, vec!["user:1".to_owned()]);
+    assert_eq!(store.scan_glob("*")?.len(), 3);
+    assert!(store.scan_glob("user")?.is_empty());
+    assert!(matches!(
+        store.scan_glob("session:[1-"),
+        Err(KvsError::InvalidPattern(_))
+    ));
+
+    Ok(())
+}
+
+// `scan_modified_since` should return keys written in the window, across reopens.
+#[test]
+fn scan_modified_since() -> Result<()> {
+    use std::time::SystemTime;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    std::thread::sleep(Duration::from_millis(5));
+    let since = SystemTime::now();
+    std::thread::sleep(Duration::from_millis(5));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let expected = vec![
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ];
+    assert_eq!(store.scan_modified_since(since)?, expected);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan_modified_since(since)?, expected);
+
+    store.remove("key2")?;
+    assert_eq!(store.scan_modified_since(since)?, expected[1..]);
+
+    Ok(())
+}
+
+// `kvs batch <FILE>` should apply every operation of the file in order.
+#[test]
+fn cli_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = temp_dir.path().join("ops.txt");
+    std::fs::write(
+        &file,
+        "set key1 value1\nset key2 value with spaces\nget key2\n\nrm key1\nget key1\nrm key3\n",
+    )?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch", "ops.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(
+            "value with spaces\nKey not found\n2 sets, 1 removes, 2 gets\n",
+        ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value with spaces").trim());
+
+    std::fs::write(&file, "set key1\n")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch", "ops.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("line 1"));
+
+    Ok(())
+}
+
+// `kvs batch` should strip CRLF line endings and trailing spaces after keys, and only
+// count removals of keys that exist.
+#[test]
+fn cli_batch_line_endings() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("ops.txt"),
+        "set key1 value1\r\nget key1 \r\nrm key1 \r\nrm key1\r\nrm key2\r\nget key1\r\n",
+    )?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch", "ops.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1\nKey not found\n1 sets, 1 removes, 2 gets\n"));
+
+    std::fs::write(temp_dir.path().join("ops.txt"), "set key1 value2\r\n")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch", "ops.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2\n"));
+
+    Ok(())
+}
+
+// `KvsError` should convert into `io::Error` with a matching kind.
+#[test]
+fn kvs_error_into_io_error() {
+    use std::io;
+
+    let err: io::Error = KvsError::Io(io::Error::from(io::ErrorKind::TimedOut)).into();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    let err: io::Error = KvsError::KeyNotFound("key1".to_owned()).into();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(err.to_string(), "key not found: key1");
+
+    let err: io::Error = KvsError::ReadOnly.into();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+    fn open(path: &std::path::Path) -> io::Result<Option<String>> {
+        Ok(KvStore::open(path)?.get("key1")?)
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(open(temp_dir.path()).unwrap(), None);
+}
+
+// `scan_index_only` should compute the index of a store without opening it.
+#[test]
+fn scan_index_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2")?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let expected = store.index_snapshot();
+    drop(store);
+
+    let index = kvs::scan_index_only(temp_dir.path())?;
+    assert_eq!(index.len(), 2);
+    assert_eq!(
+        index
+            .into_iter()
+            .collect::<std::collections::BTreeMap<_, _>>(),
+        expected
+    );
+
+    Ok(())
+}
+
+// `value_len` should report the length of the record holding a value.
+#[test]
+fn value_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let record = r#"{"Set":{"key":"key1","value":"value1","modified":"#;
+    let len = store.value_len("key1").expect("key1 was written");
+    assert!(len > record.len() as u64);
+    assert_eq!(Some(len), store.index_snapshot().get("key1").map(|p| p.len));
+
+    store.remove("key1")?;
+    assert_eq!(store.value_len("key1"), None);
+
+    Ok(())
+}
+
+// Corrupted commands should fail opening, be skipped or end the segment, as configured.
+#[test]
+fn on_corruption() -> Result<()> {
+    let first = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    let second = r#"{"Set":{"key":"key2","value":"value2"}}"#;
+
+    for (policy, keys) in [
+        (CorruptionPolicy::Fail, None),
+        (CorruptionPolicy::SkipRecord, Some(2)),
+        (CorruptionPolicy::SealAndStartNew, Some(1)),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        std::fs::write(
+            temp_dir.path().join("1.log"),
+            format!(r#"{first}{{"Set":{{"key":garbage}}}}{second}"#),
+        )?;
+
+        let options = KvStoreOptions::new().on_corruption(policy);
+        let (mut store, report) = match (
+            KvStore::open_with_report(temp_dir.path(), options.clone()),
+            keys,
+        ) {
+            (Err(KvsError::Serde(_)), None) => continue,
+            (res, _) => res?,
+        };
+        assert_eq!(report.records_replayed, keys.unwrap());
+        assert_eq!(report.records_skipped, 1);
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        let key2 = (policy == CorruptionPolicy::SkipRecord).then(|| "value2".to_owned());
+        assert_eq!(store.get("key2")?, key2);
+
+        // the corrupted segment is no longer the newest one, but must still load
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key2")?, key2);
+        assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A pipeline should execute its operations in order, reads seeing earlier writes.
+#[test]
+fn pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let results = store
+        .pipeline()
+        .get("key2".to_owned())
+        .set("key2".to_owned(), "value2".to_owned())
+        .get("key2".to_owned())
+        .remove("key1".to_owned())
+        .get("key1".to_owned())
+        .remove("key1".to_owned())
+        .execute()?;
+    assert_eq!(
+        results,
+        vec![
+            OpResult::Get(None),
+            OpResult::Set,
+            OpResult::Get(Some("value2".to_owned())),
+            OpResult::Remove(true),
+            OpResult::Get(None),
+            OpResult::Remove(false),
+        ]
+    );
+    assert_eq!(store.op_counters().sets, 2);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `drain` should return every pair and leave the store empty, also across reopening.
+#[test]
+fn drain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key2")?;
+    store.compact()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(
+        store.drain()?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(
+        store.get_status("key2".to_owned())?,
+        GetStatus::NeverWritten
+    );
+    assert_eq!(store.estimate_compaction().reclaimable_bytes, 0);
+    assert_eq!(WalkDir::new(temp_dir.path()).into_iter().count(), 2);
+
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.drain()?.len(), 1);
+    assert_eq!(store.drain()?, vec![]);
+
+    // Open from disk again and check persistent data.
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key5")?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// Should probe renames without leaving files behind, and compact through a rename.
+#[test]
+fn supports_atomic_rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.supports_atomic_rename());
+    assert_eq!(WalkDir::new(temp_dir.path()).into_iter().count(), 2);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert!(!temp_dir.path().join("compaction.tmp").exists());
+
+    let reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert!(!reader.supports_atomic_rename());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should ask about renames once, on the first compaction, and compact in place without them.
+#[cfg(feature = "fault-injection")]
+#[test]
+fn compaction_without_atomic_rename() -> Result<()> {
+    use kvs::{FaultInjector, FaultPoint};
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct NoAtomicRename(Arc<AtomicUsize>);
+
+    impl FaultInjector for NoAtomicRename {
+        fn inject(&mut self, _point: FaultPoint) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn atomic_rename(&mut self) -> Option<bool> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Some(false)
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let probes = Arc::new(AtomicUsize::new(0));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_fault_injector(NoAtomicRename(probes.clone()));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(probes.load(Ordering::SeqCst), 0);
+
+    for value in ["value2", "value3"] {
+        store.set("key1".to_owned(), value.to_owned())?;
+        store.compact()?;
+    }
+    assert_eq!(probes.load(Ordering::SeqCst), 1);
+    assert!(!store.supports_atomic_rename());
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// With a byte budget, the least recently used keys should be removed first.
+#[test]
+fn max_live_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "v".repeat(100);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), value.clone())?;
+    let len = store.value_len("key0").expect("key0 was written");
+    drop(store);
+
+    // room for three keys, as their commands are equally long
+    let options = KvStoreOptions::new().max_live_bytes(3 * len);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), value.clone())?;
+    assert_eq!(
+        store.with_value("key0".to_owned(), |value| value.len())?,
+        Some(value.len())
+    );
+
+    // key1 is the least recently used now
+    store.set("key3".to_owned(), value.clone())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get_status("key1".to_owned())?, GetStatus::Removed);
+    for key in ["key0", "key2", "key3"] {
+        assert_eq!(store.get(key)?, Some(value.clone()));
+    }
+
+    // a batch over the budget evicts down to it after all its writes
+    let mut batch = WriteBatch::new();
+    batch.set("key4".to_owned(), value.clone());
+    batch.set("key5".to_owned(), value.clone());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key2")?, None);
+    assert!(store.contains_key("key3"));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key in ["key3", "key4", "key5"] {
+        assert_eq!(store.get(key)?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// A failed eviction should leave its keys to be evicted by a later write.
+#[cfg(feature = "fault-injection")]
+#[test]
+fn max_live_bytes_failed_eviction() -> Result<()> {
+    use kvs::{FaultInjector, FaultPoint};
+    use std::io;
+
+    // fails the write of the command with the given number
+    struct FailingWriter {
+        writes: usize,
+        fail_at: usize,
+    }
+
+    impl FaultInjector for FailingWriter {
+        fn inject(&mut self, _point: FaultPoint) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_error(&mut self, len: usize) -> Option<usize> {
+            self.writes += 1;
+            (self.writes == self.fail_at).then_some(len / 2)
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "v".repeat(100);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), value.clone())?;
+    let len = store.value_len("key0").expect("key0 was written");
+    drop(store);
+
+    // room for three keys, as their commands are equally long
+    let options = KvStoreOptions::new().max_live_bytes(3 * len);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), value.clone())?;
+
+    // key3 is written, but removing key0 to make room for it fails
+    store.set_fault_injector(FailingWriter {
+        writes: 0,
+        fail_at: 2,
+    });
+    assert!(store.set("key3".to_owned(), value.clone()).is_err());
+    assert_eq!(store.get("key0")?, Some(value.clone()));
+
+    // key0 was used since, so key1 and key2 make room for key4
+    store.set("key4".to_owned(), value.clone())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, None);
+    for key in ["key0", "key3", "key4"] {
+        assert_eq!(store.get(key)?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// `migrate` should copy the live keys into a new store, keeping their write times.
+#[test]
+fn migrate() -> Result<()> {
+    use std::time::UNIX_EPOCH;
+
+    let old_dir = TempDir::new().expect("unable to create temporary working directory");
+    let new_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // a segment of bare JSON commands, written before write times were recorded
+    std::fs::write(
+        old_dir.path().join("1.log"),
+        r#"{"Set":{"key":"old","value":"value"}}"#,
+    )?;
+    let mut store = KvStore::open(old_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2")?;
+    let modified = store.last_modified("key1");
+    drop(store);
+
+    let options = KvStoreOptions::new().prefix("new-");
+    let report = kvs::migrate(old_dir.path(), new_dir.path(), options.clone())?;
+    assert_eq!(report.records_migrated, 2);
+
+    let mut store = KvStore::open_with_options(new_dir.path(), options.clone())?;
+    assert_eq!(store.get("old")?, Some("value".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.last_modified("key1"), modified);
+    assert_eq!(store.last_modified("old"), Some(UNIX_EPOCH));
+    drop(store);
+
+    assert!(matches!(
+        kvs::migrate(old_dir.path(), new_dir.path(), options),
+        Err(KvsError::Io(err)) if err.kind() == std::io::ErrorKind::AlreadyExists
+    ));
+
+    Ok(())
+}
+
+// Reads stalling for longer than the read timeout should fail with `Timeout`.
+#[cfg(unix)]
+#[test]
+fn read_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().read_timeout(Duration::from_millis(500));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(
+        store.with_value("key1".to_owned(), str::to_owned)?,
+        Some("value1".to_owned())
+    );
+    let mut value = String::new();
+    if let Some(mut reader) = store.get_reader("key1")? {
+        std::io::Read::read_to_string(&mut reader, &mut value)?;
+    }
+    assert_eq!(value, "value1");
+
+    // opening a pipe for reading blocks until it has a writer, which it never gets
+    let segment = temp_dir.path().join("1.log");
+    std::fs::remove_file(&segment)?;
+    assert!(Command::new("mkfifo").arg(&segment).status()?.success());
+
+    assert!(matches!(store.get("key1"), Err(KvsError::Timeout)));
+    assert!(matches!(
+        store.with_value("key1".to_owned(), str::len),
+        Err(KvsError::Timeout)
+    ));
+    assert!(matches!(store.get_reader("key1"), Err(KvsError::Timeout)));
+
+    Ok(())
+}
+
+// `count_by_prefix` should tally keys by the part before the separator.
+#[test]
+fn count_by_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key in ["user:1", "user:2", "user:3:name", "session:1", "plain"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("user:2")?;
+
+    let counts = store.count_by_prefix(':');
+    assert_eq!(counts.len(), 3);
+    assert_eq!(counts["user"], 2);
+    assert_eq!(counts["session"], 1);
+    assert_eq!(counts[""], 1);
+
+    Ok(())
+}
+
+// `remove_many` should remove present keys and tell which were.
+#[test]
+fn remove_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let keys = ["key1", "key4", "key3", "key1"].map(str::to_owned);
+    assert_eq!(store.remove_many(&keys)?, vec![true, false, true, false]);
+    assert_eq!(store.op_counters().removes, 2);
+    assert!(store.remove_many(&[])?.is_empty());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+
+    Ok(())
+}
+
+// Compaction should copy commands larger than its buffer in chunks.
+#[test]
+fn compaction_io_buffer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_io_buffer(16);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let value = "v".repeat(64 * 1024);
+    for iter in 0..4 {
+        store.set(format!("key{iter}"), format!("{iter}{value}"))?;
+        store.set(format!("key{iter}"), format!("{value}{iter}"))?;
+    }
+    let segment = store.index_snapshot()["key0"].segment;
+    store.compact_segment(segment)?;
+    store.compact()?;
+    assert!(store.verify()?.is_ok());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..4 {
+        assert_eq!(
+            store.get(format!("key{iter}"))?,
+            Some(format!("{value}{iter}"))
+        );
+    }
+
+    Ok(())
+}
+
+// `healthcheck` should report problems with a store directory without loading it.
+#[test]
+fn healthcheck() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let health = kvs::healthcheck(temp_dir.path())?;
+    assert!(health.ok, "{:?}", health.details);
+    assert!(health.details.is_empty());
+
+    std::fs::write(temp_dir.path().join("backup.log"), "")?;
+    let health = kvs::healthcheck(temp_dir.path())?;
+    assert!(!health.ok);
+    assert_eq!(health.details.len(), 1);
+    assert!(health.details[0].contains("backup.log"));
+
+    let health = kvs::healthcheck(temp_dir.path().join("missing"))?;
+    assert!(!health.ok);
+
+    Ok(())
+}
+
+// Size-tiered compaction should only merge a full tier of similarly sized segments.
+#[test]
+fn size_tiered_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::SizeTiered)
+        .auto_compact(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    // each compaction only seals the active segment until a tier is full
+    for iter in 0..4 {
+        store.set(format!("key{iter}"), "value".to_owned())?;
+        store.set("key".to_owned(), format!("{iter}"))?;
+        assert_eq!(store.compact()?, CompactionResult::default());
+    }
+    let estimate = store.estimate_compaction();
+    assert_eq!(estimate.segments_involved, 4);
+
+    // the four sealed segments are merged, the active one is sealed in turn
+    let result = store.compact()?;
+    assert_eq!(result.records_rewritten, 5);
+    assert_eq!(estimate.reclaimable_bytes, result.reclaimed);
+    assert_eq!(estimate.live_bytes, result.bytes_after);
+
+    // no tier is full anymore, so nothing would be merged
+    assert_eq!(store.estimate_compaction().segments_involved, 0);
+    assert!(store.verify()?.is_ok());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..4 {
+        assert_eq!(store.get(format!("key{iter}"))?, Some("value".to_owned()));
+    }
+    assert_eq!(store.get("key")?, Some("3".to_owned()));
+
+    Ok(())
+}
+
+// `history` should return every write of a key until compaction reclaims them.
+#[test]
+fn history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    for options in [
+        KvStoreOptions::new(),
+        KvStoreOptions::new().prefix("dedup-").dedup_values(true),
+    ] {
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "other".to_owned())?;
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        store.remove("key1")?;
+        store.set("key1".to_owned(), "value3".to_owned())?;
+
+        let values: Vec<_> = store
+            .history("key1")?
+            .into_iter()
+            .map(|(_, _, value)| value)
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                Some("value1".to_owned()),
+                Some("value2".to_owned()),
+                None,
+                Some("value3".to_owned()),
+            ]
+        );
+
+        store.compact()?;
+        let history = store.history("key1")?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].2, Some("value3".to_owned()));
+        assert!(store.history("key3")?.is_empty());
+    }
+
+    Ok(())
+}
+
+// A replica built from a snapshot and the segments written since should match the source.
+#[test]
+fn open_replica() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(source_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let mut base = Vec::new();
+    store.physical_backup(&mut base)?;
+
+    // later writes go to a segment of their own
+    store.compact()?;
+    store.set("key2".to_owned(), "new2".to_owned())?;
+    store.remove("key1")?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let delta = WalkDir::new(source_dir.path())
+        .into_iter()
+        .map(|entry| entry.expect("directory entry").into_path())
+        .filter(|path| path.is_file())
+        .max_by_key(|path| {
+            let name = path.file_stem().and_then(|stem| stem.to_str());
+            name.and_then(|name| name.parse::<u64>().ok())
+        })
+        .expect("active segment");
+
+    let mut replica = KvStore::open_replica(
+        replica_dir.path(),
+        KvStoreOptions::new(),
+        &mut &base[..],
+        &[delta],
+    )?;
+    assert_eq!(replica.index_snapshot().len(), 2);
+    assert_eq!(replica.get("key1")?, None);
+    assert_eq!(replica.get("key2")?, Some("new2".to_owned()));
+    assert_eq!(replica.get("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `transform_keys` should move and drop keys, the last one moved to a key winning.
+#[test]
+fn transform_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key in ["a", "b", "c", "drop", "keep", "x"] {
+        store.set(key.to_owned(), format!("value-{key}"))?;
+    }
+
+    // a moves to b while b moves on to c, and c and x both move to y
+    let transformed = store.transform_keys(|key| match key {
+        "a" => Some("b".to_owned()),
+        "b" => Some("c".to_owned()),
+        "c" | "x" => Some("y".to_owned()),
+        "drop" => None,
+        key => Some(key.to_owned()),
+    })?;
+    assert_eq!(transformed, 5);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a")?, None);
+    assert_eq!(store.get("b")?, Some("value-a".to_owned()));
+    assert_eq!(store.get("c")?, Some("value-b".to_owned()));
+    assert_eq!(store.get("y")?, Some("value-x".to_owned()));
+    assert_eq!(store.get("x")?, None);
+    assert_eq!(store.get("drop")?, None);
+    assert_eq!(store.get("keep")?, Some("value-keep".to_owned()));
+
+    Ok(())
+}
+
+// `get_reader` should stream the unescaped value of a key and stop at the end of its record.
+#[test]
+fn get_reader() -> Result<()> {
+    use std::io::Read;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let json = r#"{"name": "kvs", "tags": ["a\"b", "ünï", "🦀"], "nested": {"n": 1}}"#;
+    let tricky_key = r#"key","value":"not this"#.to_owned();
+    store.set(tricky_key.clone(), json.to_owned())?;
+    store.set("next".to_owned(), "next value".to_owned())?;
+
+    let mut value = String::new();
+    store
+        .get_reader(&tricky_key)?
+        .expect("key exists")
+        .read_to_string(&mut value)?;
+    assert_eq!(value, json);
+
+    // read a byte at a time, through escapes and multi-byte characters
+    let mut reader = store.get_reader(&tricky_key)?.expect("key exists");
+    let mut bytes = Vec::new();
+    let mut byte = [0; 1];
+    while reader.read(&mut byte)? == 1 {
+        bytes.push(byte[0]);
+    }
+    assert_eq!(bytes, json.as_bytes());
+
+    let reader = store.get_reader(&tricky_key)?.expect("key exists");
+    let parsed: serde_json::Value = serde_json::from_reader(reader).expect("value is valid JSON");
+    assert_eq!(parsed["tags"][2], "🦀");
+
+    assert!(store.get_reader("missing")?.is_none());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let mut value = String::new();
+    store
+        .get_reader("next")?
+        .expect("key exists")
+        .read_to_string(&mut value)?;
+    assert_eq!(value, "next value");
+
+    Ok(())
+}
+
+// `segment_order` should list segments ascending, with compaction output numbered after
+// every segment on disk, including ones left behind by a crashed compaction.
+#[test]
+fn segment_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().keep_recent_segments(1);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.segment_order(), vec![1, 2]);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    // an empty segment left behind by a compaction that crashed before writing
+    drop(store);
+    std::fs::write(temp_dir.path().join("7.log"), "")?;
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.segment_order(), vec![1, 2, 7, 8]);
+
+    // segments 1, 2 and 7 are compacted into segment 9, segment 8 is kept
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.segment_order(), vec![8, 9, 10]);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.segment_order(), vec![8, 9, 10, 11]);
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// `set_and_locate` should return the position of the record just written, as in the index.
+#[test]
+fn set_and_locate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let (segment, offset, len) = store.set_and_locate("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!((segment, offset), (1, 0));
+    let next = store.set_and_locate("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(next.0, segment);
+    assert_eq!(next.1, offset + len);
+
+    let position = store.index_snapshot()["key2"];
+    assert_eq!(
+        (position.segment, position.offset, position.len),
+        (next.0, next.1, next.2)
+    );
+
+    // the position holds the record itself
+    let log = std::fs::read(temp_dir.path().join(format!("{segment}.log")))?;
+    let record = &log[next.1 as usize..(next.1 + next.2) as usize];
+    assert!(String::from_utf8_lossy(record).contains("value2"));
+
+    // compaction moves records, the index tells where to
+    store.compact()?;
+    let position = store.index_snapshot()["key2"];
+    assert_ne!(position.segment, segment);
+
+    Ok(())
+}
+
+// `get_at` should read the record at a position and reject positions holding no record.
+#[test]
+fn get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let (segment, offset, len) = store.set_and_locate("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1")?;
+
+    assert_eq!(
+        store.get_at(segment, offset, len)?,
+        Some("value1".to_owned())
+    );
+
+    // every record from `dump_segment`, the removal included
+    let dumped = store.dump_segment(segment)?;
+    let removal = dumped.last().expect("segment has records").0;
+    let log_len = std::fs::metadata(temp_dir.path().join(format!("{segment}.log")))?.len();
+    assert_eq!(store.get_at(segment, removal, log_len - removal)?, None);
+
+    assert!(matches!(
+        store.get_at(42, 0, len),
+        Err(KvsError::MissingSegment(42))
+    ));
+    assert!(matches!(
+        store.get_at(segment, log_len - 1, len),
+        Err(KvsError::Corruption(_, _))
+    ));
+    assert!(matches!(
+        store.get_at(segment, offset + 1, len - 1),
+        Err(KvsError::Corruption(_, _))
+    ));
+    // two records at once
+    assert!(matches!(
+        store.get_at(segment, offset, len * 2),
+        Err(KvsError::Corruption(_, _))
+    ));
+
+    Ok(())
+}
+
+// Writes synced every few commands should behave like flushed ones, across batches,
+// compactions and reopening.
+#[test]
+fn durability_fsync_every() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().durability(Durability::FsyncEvery(3));
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1")?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    for i in 0..5 {
+        batch.set(format!("batch{i}"), format!("value{i}"));
+    }
+    store.write_batch(batch)?;
+    store.compact()?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4")?, Some("value4".to_owned()));
+    assert_eq!(store.get("batch4")?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// The offset of the active segment should match the length of its file.
+#[test]
+fn active_segment_offset() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.active_segment_offset(), 0);
+    assert_eq!(store.active_segment_file_len()?, 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1")?;
+    assert!(store.active_segment_offset() > 0);
+    assert_eq!(
+        store.active_segment_offset(),
+        store.active_segment_file_len()?
+    );
+
+    // compaction starts a new active segment
+    store.compact()?;
+    assert_eq!(store.active_segment_offset(), 0);
+    assert_eq!(store.active_segment_file_len()?, 0);
+
+    Ok(())
+}
+
+// Shrinking the index after removing most keys should leave the store as it was.
+#[test]
+fn shrink_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..1000 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    for i in 10..1000 {
+        store.remove(format!("key{i}"))?;
+    }
+
+    store.shrink_index();
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{i}"))?, Some(format!("value{i}")));
+    }
+    assert_eq!(store.get("key10")?, None);
+
+    // the store keeps working after shrinking
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    store.compact()?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key9")?, Some("value9".to_owned()));
+    assert_eq!(store.get("key10")?, Some("value10".to_owned()));
+    assert_eq!(store.get("key11")?, None);
+
+    Ok(())
+}
+
+// `OutOfSpace` should keep the IO error of the full disk, which only writes report.
+#[test]
+fn out_of_space_error() {
+    use std::io;
+
+    let err = KvsError::from(io::Error::from(io::ErrorKind::StorageFull));
+    assert!(matches!(err, KvsError::Io(_)));
+
+    #[cfg(target_os = "linux")]
+    {
+        const ENOSPC: i32 = 28;
+        let err: io::Error = KvsError::OutOfSpace(io::Error::from_raw_os_error(ENOSPC)).into();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(err.raw_os_error(), Some(ENOSPC));
+    }
+
+    let err: io::Error = KvsError::OutOfSpace(io::Error::from(io::ErrorKind::StorageFull)).into();
+    assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+}
+
+// A write failing on a full disk should be reported as `OutOfSpace` and undone.
+#[cfg(feature = "fault-injection")]
+#[test]
+fn out_of_space_write() -> Result<()> {
+    use kvs::{FaultInjector, FaultPoint};
+    use std::io;
+
+    struct FullDisk;
+
+    impl FaultInjector for FullDisk {
+        fn inject(&mut self, _point: FaultPoint) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_error(&mut self, len: usize) -> Option<usize> {
+            Some(len / 2)
+        }
+
+        fn write_error_kind(&self) -> io::ErrorKind {
+            io::ErrorKind::StorageFull
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    store.set_fault_injector(FullDisk);
+    match store.set("key1".to_owned(), "value2".to_owned()) {
+        Err(KvsError::OutOfSpace(err)) => assert_eq!(err.kind(), io::ErrorKind::StorageFull),
+        res => panic!("expected OutOfSpace, got {:?}", res),
+    }
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::OutOfSpace(_))
+    ));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A failed write should be cut off the log and leave the index as it was, so the store
+// keeps working and reopens without a torn record.
+#[cfg(feature = "fault-injection")]
+#[test]
+fn failed_write_is_undone() -> Result<()> {
+    use kvs::{FaultInjector, FaultPoint};
+    use std::io;
+
+    // fails the write of the command with the given number, after its first 5 bytes
+    struct FailingWriter {
+        writes: usize,
+        fail_at: usize,
+    }
+
+    impl FaultInjector for FailingWriter {
+        fn inject(&mut self, _point: FaultPoint) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_error(&mut self, len: usize) -> Option<usize> {
+            self.writes += 1;
+            (self.writes == self.fail_at).then_some(5.min(len))
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_len = || std::fs::metadata(temp_dir.path().join("1.log")).map(|m| m.len());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = log_len()?;
+
+    store.set_fault_injector(FailingWriter {
+        writes: 0,
+        fail_at: 1,
+    });
+    assert!(store.set("key1".to_owned(), "value2".to_owned()).is_err());
+    assert_eq!(log_len()?, len);
+    assert_eq!(store.active_segment_offset(), len);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    // the second write of a batch fails, the whole batch is undone
+    store.set_fault_injector(FailingWriter {
+        writes: 0,
+        fail_at: 2,
+    });
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    assert!(store.write_batch(batch).is_err());
+    assert_eq!(log_len()?, len);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    // later writes land where the index expects them
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let (mut store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(report.records_skipped, 0);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `compact_into` should write the live keys into a new store and leave the source alone.
+#[test]
+fn compact_into() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().dedup_values(true);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "shared".to_owned())?;
+    store.set("key2".to_owned(), "shared".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3")?;
+    let segments = store.segment_order();
+
+    store.compact_into(copy_dir.path())?;
+    assert_eq!(store.segment_order(), segments);
+    assert_eq!(store.get("key1")?, Some("shared".to_owned()));
+
+    // a single segment, without the stale records
+    let files: Vec<_> = std::fs::read_dir(copy_dir.path())?.collect();
+    assert_eq!(files.len(), 1);
+    let log = std::fs::read_to_string(copy_dir.path().join("1.log"))?;
+    assert!(!log.contains("value1"));
+    assert!(!log.contains("key3"));
+
+    let mut copy = KvStore::open_with_options(copy_dir.path(), options())?;
+    assert_eq!(copy.get("key1")?, Some("shared".to_owned()));
+    assert_eq!(copy.get("key2")?, Some("shared".to_owned()));
+    assert_eq!(copy.get("key3")?, None);
+    assert_eq!(copy.estimate_compaction().reclaimable_bytes, 0);
+
+    // a directory that already holds a store is left alone
+    drop(copy);
+    assert!(store.compact_into(copy_dir.path()).is_err());
+
+    Ok(())
+}
+
+// `MapAdapter` should behave like a `HashMap` on top of a persisted store.
+#[test]
+fn map_adapter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut map = MapAdapter::new(KvStore::open(temp_dir.path())?);
+    assert!(map.is_empty());
+
+    assert_eq!(map.insert("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        map.insert("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(map.insert("key2".to_owned(), "value3".to_owned())?, None);
+    assert_eq!(map.len(), 2);
+    assert!(map.contains_key("key1"));
+    assert_eq!(map.get("key1")?, Some("value2".to_owned()));
+
+    assert_eq!(map.remove("key2")?, Some("value3".to_owned()));
+    assert_eq!(map.remove("key2")?, None);
+    assert_eq!(map.len(), 1);
+
+    // Open from disk again and check persistent data.
+    drop(map);
+    let mut map = MapAdapter::from(KvStore::open(temp_dir.path())?);
+    assert_eq!(map.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(map.get("key2")?, None);
+
+    map.clear()?;
+    assert!(map.is_empty());
+    let mut store = map.into_inner();
+    assert_eq!(store.get("key1")?, None);
+
+    Ok(())
+}
+
+// A failed compaction should leave the garbage count as it was, so it is retried.
+#[test]
+fn failed_compaction_keeps_garbage_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_open_readers(1)
+        .auto_compact(false);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set_compaction_threshold(10);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    let segment = store.index_snapshot()["key1"].segment;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert!(store.should_compact());
+
+    // the reader of the compacted segment is closed, so compaction cannot read it
+    std::fs::remove_file(temp_dir.path().join(format!("{segment}.log")))?;
+    assert!(store.compact().is_err());
+    assert!(store.should_compact());
+
+    Ok(())
+}
+
+// Reading a key whose segment file is gone should fail with `MissingSegment`, not read
+// as absent.
+#[test]
+fn get_missing_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_open_readers(1);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let segment = store.index_snapshot()["key1"].segment;
+    assert_ne!(segment, store.index_snapshot()["key2"].segment);
+
+    // the reader of the compacted segment is closed to stay within the limit
+    std::fs::remove_file(temp_dir.path().join(format!("{segment}.log")))?;
+    assert!(matches!(
+        store.get("key1"),
+        Err(KvsError::MissingSegment(missing)) if missing == segment
+    ));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Versions should count the writes of a key, across compactions and reopening, and
+// `set_if_version` should only write at the expected version.
+#[test]
+fn get_versioned() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key1".to_owned())?, (None, 0));
+
+    assert!(store.set_if_version("key1".to_owned(), "value1".to_owned(), 0)?);
+    assert!(!store.set_if_version("key1".to_owned(), "value2".to_owned(), 0)?);
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        (Some("value1".to_owned()), 1)
+    );
+
+    // removals count too, so a key set again does not go back to an old version
+    store.remove("key1")?;
+    assert_eq!(store.get_versioned("key1".to_owned())?, (None, 2));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value3".to_owned());
+    batch.set("key1".to_owned(), "value4".to_owned());
+    store.write_batch(batch)?;
+    store.set("key2".to_owned(), "value5".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3")?;
+    store.compact()?;
+
+    // compaction keeps the versions of removed keys with their tombstones
+    assert_eq!(store.get_versioned("key3".to_owned())?, (None, 2));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        (Some("value4".to_owned()), 4)
+    );
+    assert!(!store.set_if_version("key1".to_owned(), "value6".to_owned(), 3)?);
+    assert!(store.set_if_version("key1".to_owned(), "value6".to_owned(), 4)?);
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        (Some("value6".to_owned()), 5)
+    );
+    assert_eq!(
+        store.get_versioned("key2".to_owned())?,
+        (Some("value5".to_owned()), 1)
+    );
+
+    Ok(())
+}
+
+// Should refuse to open a store at a path that exists as a file
+#[test]
+fn open_file_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let file = temp_dir.path().join("1.log");
+    assert!(matches!(
+        KvStore::open(&file),
+        Err(KvsError::NotADirectory(ref path)) if *path == file
+    ));
+    assert!(matches!(
+        KvStore::open_read_only(&file, KvStoreOptions::default()),
+        Err(KvsError::NotADirectory(_))
+    ));
+
+    // a missing directory is still created
+    let mut store = KvStore::open(temp_dir.path().join("nested").join("store"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    Ok(())
+}
+
+// Should export the live pairs of a store and import them into another
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = temp_dir.path().join("source");
+    let mut store = KvStore::open(&source)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value \"2\"\nwith a newline".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "pairs.json", "--path", "source"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("2 pairs exported\n"));
+
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("pairs.json"))?,
+        "{\"key\":\"key1\",\"value\":\"value1\"}\n\
+         {\"key\":\"key2\",\"value\":\"value \\\"2\\\"\\nwith a newline\"}\n"
+    );
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path", "target", "import", "pairs.json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("2 pairs imported\n"));
+
+    let mut store = KvStore::open(temp_dir.path().join("target"))?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get("key2")?,
+        Some("value \"2\"\nwith a newline".to_owned())
+    );
+    assert_eq!(store.get("key3")?, None);
+    drop(store);
+
+    std::fs::write(temp_dir.path().join("pairs.json"), "{\"key\":\"key4\"}\n")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "pairs.json", "--path", "target"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("line 1"));
+
+    Ok(())
+}
+
+// Should normalize keys passed to every method before they touch the index
+#[test]
+fn key_normalizer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().key_normalizer(|key| key.to_lowercase());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+
+    store.set("Key1".to_owned(), "value1".to_owned())?;
+    store.set("KEY2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("KEY1")?, Some("value1".to_owned()));
+    assert!(store.contains_key("kEy2"));
+    assert_eq!(store.first_key(), Some(&"key1".to_owned()));
+
+    store.set("KEY1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.index_snapshot().len(), 2);
+    assert_eq!(store.get("Key1")?, Some("value3".to_owned()));
+
+    let mut batch = WriteBatch::new();
+    batch.set("Key3".to_owned(), "value4".to_owned());
+    batch.remove("kEY3".to_owned());
+    batch.set("KEY4".to_owned(), "value5".to_owned());
+    store.write_batch(batch)?;
+    assert!(!store.contains_key("key3"));
+    assert_eq!(store.get("Key4")?, Some("value5".to_owned()));
+
+    let results = store
+        .pipeline()
+        .set("Key5".to_owned(), "value6".to_owned())
+        .get("KEY5".to_owned())
+        .execute()?;
+    assert_eq!(results[1], OpResult::Get(Some("value6".to_owned())));
+
+    let (segment, offset, len) = store.set_and_locate("Key6".to_owned(), "value7".to_owned())?;
+    store.index_insert_raw("ALIAS".to_owned(), segment, offset, len)?;
+    assert_eq!(store.get("alias")?, Some("value7".to_owned()));
+
+    store.remove("KEY2")?;
+    assert!(!store.contains_key("key2"));
+    assert!(matches!(
+        store.remove("key2"),
+        Err(KvsError::KeyNotFound(_))
+    ));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("KEY1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4")?, Some("value5".to_owned()));
+    assert!(store.snapshot().get("KEY5")?.is_some());
+    assert!(!store.contains_key("Key2"));
+    drop(store);
+
+    // keys are stored normalized
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("Key1"));
+
+    Ok(())
+}
+
+// `transform_keys` should move keys written before a normalizer was set to their normalized key.
+#[test]
+fn key_normalizer_transform_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("Key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("KEY2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let options = || KvStoreOptions::new().key_normalizer(|key| key.to_lowercase());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("KEY2")?, None);
+
+    // the moved key wins over the one that stays
+    assert_eq!(store.transform_keys(|key| Some(key.to_owned()))?, 2);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("Key2")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value4".to_owned()));
+    assert_eq!(store.index_snapshot().len(), 3);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let mut keys: Vec<String> = store.index_snapshot().into_keys().collect();
+    keys.sort();
+    assert_eq!(keys, ["key1", "key2", "key3"]);
+
+    Ok(())
+}
+
+// Should reserve space for the active segment without changing its records
+#[test]
+fn reserve_segment_space() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let offset = store.active_segment_offset();
+    store.reserve_segment_space(1024 * 1024)?;
+    assert_eq!(store.active_segment_offset(), offset);
+    assert_eq!(store.active_segment_file_len()?, offset);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.active_segment_file_len()?,
+        store.active_segment_offset()
+    );
+
+    let mut reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::default())?;
+    assert!(matches!(
+        reader.reserve_segment_space(1024),
+        Err(KvsError::ReadOnly)
+    ));
+    drop(reader);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}